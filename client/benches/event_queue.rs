@@ -0,0 +1,128 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use lefs::{json, Engine, Event, Lefs, QueueKind};
+
+const SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+
+// whole runs keep a log of every firing, so they stay smaller
+const SIMULATE_SIZES: [usize; 2] = [1_000, 10_000];
+const LAST_CYCLE: usize = 1_000;
+
+// a ring of transitions, each one feeding the next, with firing durations
+// spread over 1..=100 cycles like in our larger models
+fn generate(transitions: usize) -> Lefs {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut duration = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        (seed % 100) as usize + 1
+    };
+
+    let ia_red = (0..transitions)
+        .map(|i| json::Transition {
+            ii_idglobal: i,
+            ii_valor: 0,
+            ii_tiempo: 0,
+            ii_duracion_disparo: duration(),
+            ii_listactes_iul: vec![json::Payload(i as isize, 1)],
+            ii_listactes_pul: vec![json::Payload(((i + 1) % transitions) as isize, -1)],
+            ii_vecesdisparada: 0,
+            ii_grupoconflicto: None,
+            il_tiemposhastamarca: None,
+            ib_desalida: false,
+        })
+        .collect();
+
+    let lefs = json::Lefs {
+        interior: json::INTERIOR,
+        exterior: json::EXTERIOR,
+        ia_red,
+        ii_indice: transitions,
+        is_transicionessensibilizadas: vec![],
+        il_eventos: json::Lista::default(),
+        il_eventosinmediatos: json::Lista::default(),
+        il_eventos_exteriores: json::Lista::default(),
+        il_grupos_conflicto: json::Lista::default(),
+    };
+    Lefs::from_json(lefs).unwrap()
+}
+
+// the hold model: every transition starts with an event in flight, then
+// each event taken out schedules the one its transition produces on firing
+fn hold(lefs: &Lefs, queue: QueueKind) -> usize {
+    let mut events = queue.build();
+    for (i, transition) in lefs.transitions.iter().enumerate() {
+        events.push(Event {
+            cycle: transition.duration,
+            transition_index: i,
+            constant: -1,
+        });
+    }
+
+    for _ in 0..lefs.transitions.len() {
+        let event = events.pop_due(usize::MAX).unwrap();
+        let transition = &lefs.transitions[event.transition_index];
+        for payload in &transition.pul_payloads {
+            events.push(Event {
+                cycle: event.cycle + lefs.transitions[payload.transition_index].duration,
+                transition_index: payload.transition_index,
+                constant: payload.constant,
+            });
+        }
+    }
+
+    events.len()
+}
+
+fn event_queues(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hold");
+    group.sample_size(10);
+
+    for size in SIZES {
+        let lefs = generate(size);
+        for queue in [
+            QueueKind::BinaryHeap,
+            QueueKind::Calendar,
+            QueueKind::Ladder,
+        ] {
+            group.bench_with_input(
+                BenchmarkId::new(queue.to_string(), size),
+                &lefs,
+                |b, lefs| b.iter(|| hold(lefs, queue)),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+// the same queues behind the engine, firing and all
+fn simulate(c: &mut Criterion) {
+    let mut group = c.benchmark_group("simulate");
+    group.sample_size(10);
+
+    for size in SIMULATE_SIZES {
+        for queue in [
+            QueueKind::BinaryHeap,
+            QueueKind::Calendar,
+            QueueKind::Ladder,
+        ] {
+            group.bench_with_input(
+                BenchmarkId::new(queue.to_string(), size),
+                &size,
+                |b, &size| {
+                    b.iter_batched(
+                        || Engine::new(generate(size), queue),
+                        |mut engine| engine.simulate(0, LAST_CYCLE),
+                        BatchSize::LargeInput,
+                    )
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, event_queues, simulate);
+criterion_main!(benches);
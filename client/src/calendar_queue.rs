@@ -0,0 +1,175 @@
+use crate::{
+    engine::Event,
+    event_queue::{EventQueue, Scheduled},
+};
+use std::collections::VecDeque;
+
+// how many of the earliest events are sampled to size the buckets
+const SAMPLE: usize = 25;
+
+// Brown's calendar queue: events hash into buckets of a given width by their
+// cycle, the way appointments go into the days of a year, and the queue walks
+// the days from today on. Buckets are resized as the queue grows or shrinks
+// so that each day holds a couple of events.
+#[derive(Debug, Clone)]
+pub struct CalendarQueue {
+    // every bucket is kept sorted
+    buckets: Vec<VecDeque<Scheduled>>,
+    // cycles per bucket
+    width: usize,
+    // no pending event is earlier than today
+    today: usize,
+    len: usize,
+    serial: usize,
+}
+
+impl Default for CalendarQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CalendarQueue {
+    pub fn new() -> Self {
+        CalendarQueue {
+            buckets: vec![VecDeque::new(); 2],
+            width: 1,
+            today: 0,
+            len: 0,
+            serial: 0,
+        }
+    }
+
+    fn bucket(&self, cycle: usize) -> usize {
+        (cycle / self.width) % self.buckets.len()
+    }
+
+    fn insert(&mut self, scheduled: Scheduled) {
+        let bucket = self.bucket(scheduled.event.cycle);
+        let bucket = &mut self.buckets[bucket];
+        let position = bucket.partition_point(|pending| *pending < scheduled);
+        bucket.insert(position, scheduled);
+    }
+
+    // bucket holding the earliest event
+    fn earliest(&self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        // the first event found on its own day, walking a year from today
+        let day = self.today / self.width;
+        for i in 0..self.buckets.len() {
+            let bucket = (day + i) % self.buckets.len();
+            let end = (day + i + 1) * self.width;
+            if self.buckets[bucket]
+                .front()
+                .is_some_and(|scheduled| scheduled.event.cycle < end)
+            {
+                return Some(bucket);
+            }
+        }
+
+        // the calendar is sparse, look at every bucket
+        (0..self.buckets.len())
+            .filter(|&bucket| !self.buckets[bucket].is_empty())
+            .min_by_key(|&bucket| self.buckets[bucket].front().unwrap().key())
+    }
+
+    fn resize(&mut self, bucket_count: usize) {
+        let mut scheduled: Vec<Scheduled> =
+            self.buckets.iter_mut().flat_map(|b| b.drain(..)).collect();
+        scheduled.sort();
+
+        // about three events per bucket, going by the earliest ones
+        let sample = &scheduled[..scheduled.len().min(SAMPLE)];
+        self.width = match sample {
+            [first, .., last] => {
+                let separation = (last.event.cycle - first.event.cycle) / (sample.len() - 1);
+                (3 * separation).max(1)
+            }
+            _ => 1,
+        };
+        self.buckets = vec![VecDeque::new(); bucket_count];
+        for scheduled in scheduled {
+            self.insert(scheduled);
+        }
+    }
+}
+
+impl EventQueue for CalendarQueue {
+    fn push(&mut self, event: Event) {
+        self.today = if self.len == 0 {
+            event.cycle
+        } else {
+            self.today.min(event.cycle)
+        };
+        let serial = self.serial;
+        self.serial += 1;
+        self.insert(Scheduled { serial, event });
+
+        self.len += 1;
+        if self.len > 2 * self.buckets.len() {
+            self.resize(2 * self.buckets.len());
+        }
+    }
+
+    fn next_cycle(&self) -> Option<usize> {
+        let bucket = self.earliest()?;
+        self.buckets[bucket]
+            .front()
+            .map(|scheduled| scheduled.event.cycle)
+    }
+
+    fn pop_due(&mut self, cycle: usize) -> Option<Event> {
+        let bucket = self.earliest()?;
+        if self.buckets[bucket].front()?.event.cycle > cycle {
+            return None;
+        }
+
+        let scheduled = self.buckets[bucket].pop_front()?;
+        self.today = scheduled.event.cycle;
+        self.len -= 1;
+        if self.buckets.len() > 2 && self.len < self.buckets.len() / 2 {
+            self.resize(self.buckets.len() / 2);
+        }
+
+        Some(scheduled.event)
+    }
+
+    fn remove(&mut self, predicate: &dyn Fn(&Event) -> bool) -> Option<Event> {
+        let (bucket, position) = self
+            .buckets
+            .iter()
+            .enumerate()
+            .flat_map(|(bucket, scheduled)| {
+                scheduled
+                    .iter()
+                    .enumerate()
+                    .map(move |(position, scheduled)| (bucket, position, scheduled))
+            })
+            .filter(|(_, _, scheduled)| predicate(&scheduled.event))
+            .min_by_key(|(_, _, scheduled)| scheduled.key())
+            .map(|(bucket, position, _)| (bucket, position))?;
+
+        self.len -= 1;
+        self.buckets[bucket]
+            .remove(position)
+            .map(|scheduled| scheduled.event)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Event> + '_> {
+        let mut scheduled: Vec<&Scheduled> = self.buckets.iter().flatten().collect();
+        scheduled.sort();
+
+        Box::new(scheduled.into_iter().map(|scheduled| &scheduled.event))
+    }
+
+    fn box_clone(&self) -> Box<dyn EventQueue> {
+        Box::new(self.clone())
+    }
+}
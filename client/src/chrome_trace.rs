@@ -0,0 +1,135 @@
+use crate::{engine::Engine, error::Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{fs::File, io::BufWriter};
+
+// Timeline of a run in the Chrome trace event format, for chrome://tracing
+// or Perfetto. A cycle takes a microsecond on the timeline, every transition
+// gets its own track, and firings are slices lasting the transition's
+// duration. Events sent to or received from other subnets are the two ends
+// of flow arrows; each node writes its own file, merging them, e.g. with
+// jq -s '{traceEvents: map(.traceEvents) | add}', joins the arrows up.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChromeTrace {
+    #[serde(rename = "traceEvents")]
+    pub trace_events: Vec<TraceEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cat: Option<&'static str>,
+    pub ph: &'static str,
+    pub ts: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<usize>,
+    pub pid: usize,
+    pub tid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    // flow ends bind to the slice they fall in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bp: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Value>,
+}
+
+impl TraceEvent {
+    fn new(name: String, ph: &'static str, ts: usize, pid: usize, tid: usize) -> Self {
+        TraceEvent {
+            name,
+            cat: None,
+            ph,
+            ts,
+            dur: None,
+            pid,
+            tid,
+            id: None,
+            bp: None,
+            args: None,
+        }
+    }
+
+    // one end of the arrow for an event landing on transition_id at cycle,
+    // both nodes come up with the same id for it, though identical events
+    // from different senders end up sharing it
+    fn flow(
+        ph: &'static str,
+        ts: usize,
+        pid: usize,
+        tid: usize,
+        event: (usize, usize, isize),
+    ) -> Self {
+        let (transition_id, cycle, constant) = event;
+        TraceEvent {
+            cat: Some("event"),
+            id: Some(format!("{transition_id}@{cycle}:{constant}")),
+            bp: Some("e"),
+            ..TraceEvent::new("event".to_string(), ph, ts, pid, tid)
+        }
+    }
+}
+
+impl ChromeTrace {
+    // subnet names the process the tracks belong to
+    pub fn new(engine: &Engine, subnet: &str) -> Self {
+        let transitions = &engine.lefs.transitions;
+        // global ids are unique across subnets, so the lowest one tells
+        // processes apart once the files of several nodes are merged
+        let pid = transitions.iter().map(|t| t.id).min().unwrap_or(0);
+
+        let mut trace_events = vec![TraceEvent {
+            args: Some(json!({ "name": subnet })),
+            ..TraceEvent::new("process_name".to_string(), "M", 0, pid, 0)
+        }];
+        for transition in transitions {
+            trace_events.push(TraceEvent {
+                args: Some(json!({ "name": format!("transition {}", transition.id) })),
+                ..TraceEvent::new("thread_name".to_string(), "M", 0, pid, transition.id)
+            });
+        }
+
+        for log in &engine.logs {
+            let transition = &transitions[log.estimulated_transition_index];
+            trace_events.push(TraceEvent {
+                cat: Some("fire"),
+                dur: Some(transition.duration),
+                args: Some(json!({ "index": log.estimulated_transition_index })),
+                ..TraceEvent::new(
+                    format!("transition {}", transition.id),
+                    "X",
+                    log.cycle,
+                    pid,
+                    transition.id,
+                )
+            });
+
+            let arrival = log.cycle + transition.duration;
+            for payload in &transition.external_payloads {
+                let event = (payload.transition_id, arrival, payload.constant);
+                trace_events.push(TraceEvent::flow("s", log.cycle, pid, transition.id, event));
+            }
+        }
+
+        // late events moved forward by an unsynchronized node lose their arrow
+        for event in &engine.received {
+            let arrival = (event.transition_id, event.cycle, event.constant);
+            trace_events.push(TraceEvent::flow(
+                "f",
+                event.cycle,
+                pid,
+                event.transition_id,
+                arrival,
+            ));
+        }
+
+        ChromeTrace { trace_events }
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+}
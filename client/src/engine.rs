@@ -1,60 +1,157 @@
-use std::collections::VecDeque;
-
-use crate::polyfill::Lefs;
+use crate::{
+    error::{AppError, Result},
+    event_queue::{EventQueue, QueueKind},
+    polyfill::{Lefs, Transition},
+    report::SimulationReport,
+    trace::{TraceKind, TraceWriter},
+};
 use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fmt::Display,
+    fs::File,
+    io::BufWriter,
+    str::FromStr,
+};
+use tracing::{debug, debug_span, enabled, info, trace, trace_span, Level};
 
 #[derive(Debug)]
 pub struct Engine {
     pub cycle: usize,
     pub lefs: Lefs,
-    pub events: VecDeque<Event>,
+    pub events: Box<dyn EventQueue>,
+    pub external_events: VecDeque<ExternalEvent>,
     pub logs: Vec<Log>,
     pub event_count: usize,
+    pub conflict_policy: ConflictPolicy,
+    // state of the generator behind ConflictPolicy::Random
+    rng: u64,
+    // transitions that may be enabled now or later, the rest keep a cycle
+    // already gone by and need an event or a payload to get enabled again
+    candidates: BTreeSet<usize>,
+    // events other subnets sent us, as they were scheduled
+    pub received: Vec<ExternalEvent>,
+    // what's left of the logs and received events below GVT
+    pub fossils: Fossils,
+    // a timeline needs every log and received event, the report only totals
+    pub keep_history: bool,
+    // where firings and constant changes are streamed to, if anywhere
+    pub trace: Option<TraceWriter>,
 }
 
 impl Engine {
-    pub fn new(lefs: Lefs) -> Self {
-        Engine {
-            cycle: 0,
+    // queue picks the structure pending events are kept in
+    pub fn new(mut lefs: Lefs, queue: QueueKind) -> Self {
+        // a net saved halfway resumes at the latest cycle it reached
+        let cycle = lefs
+            .transitions
+            .iter()
+            .map(|transition| transition.cycle)
+            .max()
+            .unwrap_or(0);
+        let mut events = queue.build();
+        for event in std::mem::take(&mut lefs.events) {
+            events.push(event);
+        }
+        let external_events = std::mem::take(&mut lefs.external_events).into();
+        let immediate_events = std::mem::take(&mut lefs.immediate_events);
+        let candidates = (0..lefs.transitions.len()).collect();
+
+        let mut engine = Engine {
+            cycle,
             lefs,
-            events: VecDeque::new(),
+            events,
+            external_events,
             logs: vec![],
             event_count: 0,
+            conflict_policy: ConflictPolicy::FirstByIndex,
+            rng: 0,
+            candidates,
+            received: vec![],
+            fossils: Fossils::default(),
+            keep_history: false,
+            trace: None,
+        };
+        for event in immediate_events {
+            engine.apply(event);
         }
+        engine
+    }
+
+    pub fn with_conflict_policy(mut self, conflict_policy: ConflictPolicy) -> Self {
+        if let ConflictPolicy::Random(seed) = conflict_policy {
+            self.rng = seed;
+        }
+        self.conflict_policy = conflict_policy;
+        self
+    }
+
+    pub fn with_trace(mut self, trace: TraceWriter) -> Self {
+        self.trace = Some(trace);
+        self
     }
 
     // SimularPeriodo
-    pub fn simulate(&mut self, first_cycle: usize, last_cycle: usize) {
+    pub fn simulate(&mut self, first_cycle: usize, last_cycle: usize) -> SimulationReport {
         let start = Utc::now();
         self.cycle = first_cycle;
 
-        for _ in first_cycle..last_cycle {
-            println!("RELOJ LOCAL !!!  = {}", self.cycle);
-            println!("{}", self.lefs);
+        // every step jumps to the next cycle something happens at
+        while self.cycle < last_cycle {
+            let _cycle = debug_span!("cycle", cycle = self.cycle).entered();
+            trace!("RELOJ LOCAL !!!  = {}", self.cycle);
+            trace!("{}", self.lefs);
             // simularUnpaso
             self.step(last_cycle);
         }
 
-        println!("event_count: {}", self.event_count);
-        let elapsed = Utc::now() - start;
-        println!(
-            "elapsed: {:?} microseconds",
-            elapsed.num_nanoseconds().unwrap() / 1000
-        );
+        self.summarize(start, first_cycle, last_cycle)
+    }
+
+    // reports on the run from first_cycle to last_cycle that started at start
+    pub fn summarize(
+        &self,
+        start: DateTime<Utc>,
+        first_cycle: usize,
+        last_cycle: usize,
+    ) -> SimulationReport {
+        let report = SimulationReport::new(self, start, first_cycle, last_cycle);
+        info!("{report}");
+        report
+    }
+
+    // writes the current state as a LEF model file, simulating it resumes
+    // the run from here
+    pub fn save(&self, path: &str) -> Result<()> {
+        let lefs = self.lefs.to_json(self.events.iter(), &self.external_events);
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &lefs)?;
+        Ok(())
     }
 
     // simularUnpaso
-    fn step(&mut self, last_cycle: usize) {
+    pub fn step(&mut self, last_cycle: usize) {
+        self.fire_estimulated();
+        self.advance(last_cycle);
+    }
+
+    // fires every transition enabled at the current cycle
+    pub fn fire_estimulated(&mut self) {
         // actualizaSensibilizadas
         self.pep();
+        self.resolve_conflicts();
 
-        println!("-----------Stack de transiciones sensibilizadas---------");
-        println!("{:?}", self.lefs.estimulated_transition_indices);
-        println!("-----------Final Stack de transiciones---------");
+        trace!(
+            "-----------Stack de transiciones sensibilizadas---------\n{:?}\n-----------Final Stack de transiciones---------",
+            self.lefs.estimulated_transition_indices
+        );
 
         while let Some(estimulated_transition_index) =
             self.lefs.estimulated_transition_indices.pop()
         {
+            let _transition =
+                trace_span!("transition", index = estimulated_transition_index).entered();
             self.fire(estimulated_transition_index);
             self.logs.push(Log {
                 estimulated_transition_index,
@@ -62,38 +159,125 @@ impl Engine {
             })
         }
 
-        println!("-----------Lista eventos después de disparos---------");
-        println!("Estructura EventList");
-        for (i, event) in self.events.iter().enumerate() {
-            println!("  Evento -> {i}");
-            println!("{event}");
+        // listing the events takes sorting them, only worth it when shown
+        if enabled!(Level::TRACE) {
+            trace!("-----------Lista eventos después de disparos---------");
+            trace!("Estructura EventList");
+            for (i, event) in self.events.iter().enumerate() {
+                trace!("  Evento -> {i}\n{event}");
+            }
+            trace!("-----------Final lista eventos---------");
         }
-        println!("-----------Final lista eventos---------");
+    }
 
-        self.cycle = if let Some(event) = self.events.front() {
-            event.cycle
-        } else {
-            last_cycle
+    // moves the clock to the next event and applies the events due then,
+    // events past last_cycle stay pending
+    pub fn advance(&mut self, last_cycle: usize) {
+        self.cycle = match self.events.next_cycle() {
+            Some(cycle) if cycle < last_cycle => cycle,
+            _ => last_cycle,
         };
-        println!("NEXT CLOCK...... : {}", self.cycle);
+        debug!("NEXT CLOCK...... : {}", self.cycle);
 
         self.aftermath();
     }
 
+    // only looks at transitions touched since their cycle went by, so the
+    // cost follows the activity rather than the size of the net
     fn pep(&mut self) {
-        for (i, transition) in self.lefs.transitions.iter().enumerate() {
-            if transition.constant <= 0 && transition.cycle == self.cycle {
+        let transitions = &self.lefs.transitions;
+        let cycle = self.cycle;
+        self.candidates.retain(|&i| transitions[i].cycle >= cycle);
+
+        for &i in &self.candidates {
+            let transition = &transitions[i];
+            if transition.constant <= 0 && transition.cycle == cycle {
                 self.lefs.estimulated_transition_indices.push(i)
             }
         }
     }
 
+    // transitions of the same conflict group compete for the same marks,
+    // only one of them may fire per instant
+    fn resolve_conflicts(&mut self) {
+        // ordered so random draws don't depend on hashing
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut winners = HashSet::new();
+        for &i in &self.lefs.estimulated_transition_indices {
+            // a group il_grupos_conflicto doesn't list with several members
+            // is just a label
+            let competing = |&group: &usize| {
+                self.lefs
+                    .conflict_groups
+                    .get(group)
+                    .is_some_and(|members| members.len() > 1)
+            };
+            match self.lefs.transitions[i].conflict_group.filter(competing) {
+                Some(group) => groups.entry(group).or_default().push(i),
+                None => {
+                    winners.insert(i);
+                }
+            }
+        }
+
+        for (group, candidates) in groups {
+            let winner = self.pick(group, candidates);
+            winners.insert(winner);
+        }
+        self.lefs
+            .estimulated_transition_indices
+            .retain(|i| winners.contains(i));
+    }
+
+    fn pick(&mut self, group: usize, candidates: Vec<usize>) -> usize {
+        if candidates.len() == 1 {
+            return candidates[0];
+        }
+
+        match self.conflict_policy {
+            ConflictPolicy::FirstByIndex => candidates.into_iter().min().unwrap(),
+            // transitions missing from il_grupos_conflicto come last
+            ConflictPolicy::Priority => {
+                let priorities = self.lefs.conflict_groups.get(group);
+                candidates
+                    .into_iter()
+                    .min_by_key(|i| {
+                        priorities
+                            .and_then(|priorities| priorities.iter().position(|j| j == i))
+                            .unwrap_or(usize::MAX)
+                    })
+                    .unwrap()
+            }
+            ConflictPolicy::Random(_) => {
+                let draw = self.next_random() % candidates.len() as u64;
+                candidates[draw as usize]
+            }
+        }
+    }
+
+    // splitmix64, good enough to break ties and reproducible from a seed
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
     // dispararTransicion
     fn fire(&mut self, estimulated_transition_index: usize) {
+        self.lefs.transitions[estimulated_transition_index].fire_count += 1;
         let transition = self.lefs.transitions[estimulated_transition_index].clone();
+        self.record(TraceKind::Fired, estimulated_transition_index, None);
 
         for payload in &transition.iul_payloads {
             self.lefs.transitions[payload.transition_index].constant += payload.constant;
+            self.candidates.insert(payload.transition_index);
+            self.record(
+                TraceKind::Constant,
+                payload.transition_index,
+                Some(payload.constant),
+            );
         }
 
         let cycle = transition.cycle + transition.duration;
@@ -103,28 +287,378 @@ impl Engine {
                 transition_index: payload.transition_index,
                 constant: payload.constant,
             };
-            self.events.push_front(event);
+            self.events.push(event);
+        }
+
+        // events for transitions living in other subnets are queued apart,
+        // it's up to whoever drives the engine to deliver them
+        for payload in &transition.external_payloads {
+            let event = ExternalEvent {
+                cycle,
+                transition_id: payload.transition_id,
+                constant: payload.constant,
+            };
+            self.external_events.push_back(event);
+        }
+    }
+
+    // earliest pending event, if any
+    pub fn next_cycle(&self) -> Option<usize> {
+        self.events.next_cycle()
+    }
+
+    // moves the clock to cycle applying only the events due by then, unlike
+    // advance it never looks past the given cycle
+    pub fn advance_to(&mut self, cycle: usize) {
+        self.cycle = cycle;
+        debug!("NEXT CLOCK...... : {}", self.cycle);
+
+        self.aftermath();
+    }
+
+    // everything a later rollback needs to bring the engine back here
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            cycle: self.cycle,
+            transitions: self.lefs.transitions.clone(),
+            events: self.events.clone(),
+            log_count: self.fossils.log_count + self.logs.len(),
+            event_count: self.event_count,
+            rng: self.rng,
+            candidates: self.candidates.clone(),
+            received_count: self.fossils.received_count + self.received.len(),
+        }
+    }
+
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.cycle = checkpoint.cycle;
+        self.lefs.transitions = checkpoint.transitions;
+        self.events = checkpoint.events;
+        self.logs
+            .truncate(checkpoint.log_count - self.fossils.log_count);
+        self.event_count = checkpoint.event_count;
+        self.rng = checkpoint.rng;
+        self.candidates = checkpoint.candidates;
+        self.received
+            .truncate(checkpoint.received_count - self.fossils.received_count);
+
+        // what was traced since can't be taken back, readers drop it instead
+        if let Some(trace) = &mut self.trace {
+            trace.record(self.cycle, TraceKind::Rollback, None, None, None);
+        }
+    }
+
+    // folds the firings over by gvt into totals and drops the events received
+    // for cycles before it, no rollback can undo them anymore; only the
+    // leading ones go, checkpoints count on the rest staying in place
+    pub fn collect_fossils(&mut self, gvt: usize) {
+        if self.keep_history {
+            return;
+        }
+
+        let transitions = &self.lefs.transitions;
+        let over = self
+            .logs
+            .iter()
+            .take_while(|log| {
+                log.cycle + transitions[log.estimulated_transition_index].duration <= gvt
+            })
+            .count();
+        let firings = &mut self.fossils.firings;
+        firings.resize(transitions.len(), Firings::default());
+        for log in self.logs.drain(..over) {
+            let index = log.estimulated_transition_index;
+            firings[index].count += 1;
+            firings[index].first.get_or_insert(log.cycle);
+            firings[index].last = Some(log.cycle);
+            firings[index].busy += transitions[index].duration;
+        }
+        self.fossils.log_count += over;
+
+        let gone = self
+            .received
+            .iter()
+            .take_while(|event| event.cycle < gvt)
+            .count();
+        self.received.drain(..gone);
+        self.fossils.received_count += gone;
+    }
+
+    // drops a pending event coming from another subnet, false if there's
+    // no such event
+    pub fn unschedule(&mut self, event: &ExternalEvent) -> bool {
+        let transition_index = self.lefs.indices.get(&event.transition_id);
+        let removed = self
+            .events
+            .remove(&|pending: &Event| {
+                Some(&pending.transition_index) == transition_index
+                    && pending.cycle == event.cycle
+                    && pending.constant == event.constant
+            })
+            .is_some();
+
+        if removed {
+            let position = self.received.iter().rposition(|received| {
+                received.transition_id == event.transition_id
+                    && received.cycle == event.cycle
+                    && received.constant == event.constant
+            });
+            if let Some(position) = position {
+                self.received.remove(position);
+            }
         }
+        removed
+    }
+
+    // queues an event coming from another subnet
+    pub fn schedule(&mut self, event: ExternalEvent) -> Result<()> {
+        let transition_index = *self
+            .lefs
+            .indices
+            .get(&event.transition_id)
+            .ok_or(AppError::UnknownTransition(event.transition_id as isize))?;
+
+        self.events.push(Event {
+            cycle: event.cycle,
+            transition_index,
+            constant: event.constant,
+        });
+        self.received.push(event);
+
+        Ok(())
     }
 
+    // applies the events due by the current cycle
     fn aftermath(&mut self) {
-        while let Some(event) = self.events.pop_front() {
-            self.lefs.transitions[event.transition_index].constant += event.constant;
-            self.lefs.transitions[event.transition_index].cycle = event.cycle;
-            self.event_count += 1;
+        while let Some(event) = self.events.pop_due(self.cycle) {
+            self.apply(event);
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        self.lefs.transitions[event.transition_index].constant += event.constant;
+        self.lefs.transitions[event.transition_index].cycle = event.cycle;
+        self.candidates.insert(event.transition_index);
+        self.event_count += 1;
+        self.record(
+            TraceKind::Event,
+            event.transition_index,
+            Some(event.constant),
+        );
+    }
+
+    // traces what happened to a transition along with the constant it was
+    // left with
+    fn record(&mut self, kind: TraceKind, transition_index: usize, delta: Option<isize>) {
+        if let Some(trace) = &mut self.trace {
+            let transition = &self.lefs.transitions[transition_index];
+            trace.record(
+                self.cycle,
+                kind,
+                Some(transition.id),
+                delta,
+                Some(transition.constant),
+            );
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Event {
     pub cycle: usize,
     pub transition_index: usize,
     pub constant: isize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalEvent {
+    pub cycle: usize,
+    pub transition_id: usize,
+    pub constant: isize,
+}
+
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub cycle: usize,
+    pub transitions: Vec<Transition>,
+    pub events: Box<dyn EventQueue>,
+    pub log_count: usize,
+    pub event_count: usize,
+    pub rng: u64,
+    pub candidates: BTreeSet<usize>,
+    pub received_count: usize,
+}
+
+// how to pick the one transition that fires among enabled transitions of
+// the same conflict group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictPolicy {
+    // lowest position in ia_red
+    FirstByIndex,
+    // first listed in il_grupos_conflicto
+    Priority,
+    // uniformly at random, seeded for reproducible runs
+    Random(u64),
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || AppError::UnknownValue {
+            setting: "conflict policy",
+            value: s.to_string(),
+            expected: "first, priority or random <seed>",
+        };
+        let mut words = s.split_whitespace();
+        let policy = match (words.next(), words.next()) {
+            (Some("first"), None) => ConflictPolicy::FirstByIndex,
+            (Some("priority"), None) => ConflictPolicy::Priority,
+            (Some("random"), Some(seed)) => {
+                ConflictPolicy::Random(seed.parse().map_err(|_| invalid())?)
+            }
+            _ => return Err(invalid()),
+        };
+
+        match words.next() {
+            None => Ok(policy),
+            Some(_) => Err(invalid()),
+        }
+    }
+}
+
+impl Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictPolicy::FirstByIndex => write!(f, "first"),
+            ConflictPolicy::Priority => write!(f, "priority"),
+            ConflictPolicy::Random(seed) => write!(f, "random {seed}"),
+        }
+    }
+}
+
+// Logs and received events fossil collection dropped, firings totalled for
+// the report
+#[derive(Debug, Default)]
+pub struct Fossils {
+    pub log_count: usize,
+    pub received_count: usize,
+    // transition index -> its firings among the logs dropped
+    pub firings: Vec<Firings>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Firings {
+    pub count: usize,
+    pub first: Option<usize>,
+    pub last: Option<usize>,
+    // cycles spent firing
+    pub busy: usize,
+}
+
 #[derive(Debug)]
 pub struct Log {
     pub estimulated_transition_index: usize,
     pub cycle: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    // transitions 0 to 4 all enabled at cycle 0: 0, 1 and 2 compete in group
+    // 0, listed by priority as 2, 0, 1; 3 and 4 are labelled group 1, which
+    // only lists 3
+    fn engine(conflict_policy: ConflictPolicy) -> Engine {
+        let groups = [Some(0), Some(0), Some(0), Some(1), Some(1)];
+        let ia_red = groups
+            .into_iter()
+            .enumerate()
+            .map(|(id, group)| json::Transition {
+                ii_idglobal: id,
+                ii_valor: 0,
+                ii_tiempo: 0,
+                ii_duracion_disparo: 1,
+                ii_listactes_iul: vec![],
+                ii_listactes_pul: vec![],
+                ii_vecesdisparada: 0,
+                ii_grupoconflicto: group,
+                il_tiemposhastamarca: None,
+                ib_desalida: false,
+            })
+            .collect();
+        let lefs = json::Lefs {
+            interior: json::INTERIOR,
+            exterior: json::EXTERIOR,
+            ia_red,
+            ii_indice: 5,
+            is_transicionessensibilizadas: vec![],
+            il_eventos: json::Lista::default(),
+            il_eventosinmediatos: json::Lista::default(),
+            il_eventos_exteriores: json::Lista::default(),
+            il_grupos_conflicto: json::Lista {
+                il_milista: vec![vec![2, 0, 1], vec![3]],
+            },
+        };
+
+        let lefs = Lefs::from_json(lefs).unwrap();
+        Engine::new(lefs, QueueKind::default()).with_conflict_policy(conflict_policy)
+    }
+
+    // ids of the transitions that fired at cycle 0
+    fn fired(conflict_policy: ConflictPolicy) -> Vec<usize> {
+        let mut engine = engine(conflict_policy);
+        engine.fire_estimulated();
+        let transitions = engine.lefs.transitions.iter();
+        transitions
+            .filter(|t| t.fire_count > 0)
+            .map(|t| t.id)
+            .collect()
+    }
+
+    #[test]
+    fn first_by_index() {
+        assert_eq!(fired(ConflictPolicy::FirstByIndex), [0, 3, 4]);
+    }
+
+    #[test]
+    fn priority() {
+        assert_eq!(fired(ConflictPolicy::Priority), [2, 3, 4]);
+    }
+
+    #[test]
+    fn random() {
+        let winners: HashSet<usize> = (0..32)
+            .map(|seed| {
+                let winner = fired(ConflictPolicy::Random(seed));
+                assert_eq!(winner, fired(ConflictPolicy::Random(seed)));
+                assert_eq!(winner[1..], [3, 4]);
+                winner[0]
+            })
+            .collect();
+        assert_eq!(winners, HashSet::from([0, 1, 2]));
+    }
+
+    #[test]
+    fn policies_parse() {
+        assert_eq!(
+            "first".parse::<ConflictPolicy>().unwrap(),
+            ConflictPolicy::FirstByIndex
+        );
+        assert_eq!(
+            "priority".parse::<ConflictPolicy>().unwrap(),
+            ConflictPolicy::Priority
+        );
+        assert_eq!(
+            "random 7".parse::<ConflictPolicy>().unwrap(),
+            ConflictPolicy::Random(7)
+        );
+        for policy in ["", "last", "random", "random x", "first 1"] {
+            assert!(matches!(
+                policy.parse::<ConflictPolicy>(),
+                Err(AppError::UnknownValue { .. })
+            ));
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::validate::Diagnostic;
 use std::{error::Error, fmt::Display};
 
 pub type Result<T> = std::result::Result<T, AppError>;
@@ -6,6 +7,48 @@ pub type Result<T> = std::result::Result<T, AppError>;
 pub enum AppError {
     Io(std::io::Error),
     SerdeJson(serde_json::Error),
+    UnknownTransition(isize),
+    InvalidMessage(String),
+    // command line the binary can't make sense of
+    InvalidArguments(String),
+    // a setting given on the command line or in a manifest that isn't one of
+    // the expected values
+    UnknownValue {
+        setting: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    // the peer speaks another version of the protocol
+    UnsupportedVersion(u16),
+    // the peer couldn't read what we sent it
+    Rejected(String),
+    // nobody listening at address
+    Unreachable {
+        address: String,
+        error: std::io::Error,
+    },
+    // nothing passes messages on to us anymore
+    ListenerGone,
+    // a job wants a file written outside the client's output directory
+    OutputNotAllowed(String),
+    // the coordinator called the run off
+    Aborted(String),
+    // the model received isn't the one the job asked for
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    // the model would take more than limit bytes once decompressed
+    ModelTooLarge {
+        limit: usize,
+    },
+    // the model file loads but can't be simulated
+    InvalidModel(Vec<Diagnostic>),
+    // what went wrong with the file at path
+    File {
+        path: String,
+        error: Box<AppError>,
+    },
 }
 
 impl Error for AppError {}
@@ -15,6 +58,38 @@ impl Display for AppError {
         match self {
             Self::Io(error) => write!(f, "{}", error),
             Self::SerdeJson(error) => write!(f, "{}", error),
+            Self::UnknownTransition(index) => {
+                write!(f, "payload references unknown transition {}", index)
+            }
+            Self::InvalidMessage(message) => write!(f, "invalid message: {:?}", message),
+            Self::InvalidArguments(args) => write!(f, "invalid arguments: {:?}", args),
+            Self::UnknownValue {
+                setting,
+                value,
+                expected,
+            } => write!(f, "unknown {} {:?}, expected {}", setting, value, expected),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            Self::Rejected(message) => write!(f, "rejected: {}", message),
+            Self::Unreachable { address, error } => {
+                write!(f, "can't reach {}: {}", address, error)
+            }
+            Self::ListenerGone => write!(f, "listener gone"),
+            Self::OutputNotAllowed(name) => write!(f, "output {:?} not allowed", name),
+            Self::Aborted(reason) => write!(f, "aborted: {}", reason),
+            Self::HashMismatch { expected, actual } => {
+                write!(f, "expected model {} but got {}", expected, actual)
+            }
+            Self::ModelTooLarge { limit } => write!(f, "model larger than {} bytes", limit),
+            Self::InvalidModel(diagnostics) => {
+                write!(f, "invalid model")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n  {}", diagnostic)?;
+                }
+                Ok(())
+            }
+            Self::File { path, error } => write!(f, "{}: {}", path, error),
         }
     }
 }
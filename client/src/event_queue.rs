@@ -0,0 +1,294 @@
+use crate::{
+    calendar_queue::CalendarQueue,
+    engine::Event,
+    error::{AppError, Result},
+    ladder_queue::LadderQueue,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
+// Future event list: pending events ordered by cycle, events sharing a cycle
+// come out in the order they were pushed
+pub trait EventQueue: Debug {
+    fn push(&mut self, event: Event);
+
+    // cycle of the earliest pending event
+    fn next_cycle(&self) -> Option<usize>;
+
+    // earliest pending event, as long as it's due by cycle
+    fn pop_due(&mut self, cycle: usize) -> Option<Event>;
+
+    // takes out the earliest event matching predicate
+    fn remove(&mut self, predicate: &dyn Fn(&Event) -> bool) -> Option<Event>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // pending events in the order they'll be applied
+    fn iter(&self) -> Box<dyn Iterator<Item = &Event> + '_>;
+
+    // checkpoints need a copy of whatever queue the engine runs on
+    fn box_clone(&self) -> Box<dyn EventQueue>;
+}
+
+impl Clone for Box<dyn EventQueue> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+// which EventQueue the engine keeps its pending events in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum QueueKind {
+    #[default]
+    BinaryHeap,
+    Calendar,
+    Ladder,
+}
+
+impl QueueKind {
+    pub fn build(self) -> Box<dyn EventQueue> {
+        match self {
+            QueueKind::BinaryHeap => Box::new(BinaryHeapQueue::new()),
+            QueueKind::Calendar => Box::new(CalendarQueue::new()),
+            QueueKind::Ladder => Box::new(LadderQueue::new()),
+        }
+    }
+}
+
+impl FromStr for QueueKind {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "heap" => Ok(QueueKind::BinaryHeap),
+            "calendar" => Ok(QueueKind::Calendar),
+            "ladder" => Ok(QueueKind::Ladder),
+            _ => Err(AppError::UnknownValue {
+                setting: "queue",
+                value: s.to_string(),
+                expected: "heap, calendar or ladder",
+            }),
+        }
+    }
+}
+
+impl Display for QueueKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueKind::BinaryHeap => write!(f, "heap"),
+            QueueKind::Calendar => write!(f, "calendar"),
+            QueueKind::Ladder => write!(f, "ladder"),
+        }
+    }
+}
+
+// an event tagged with its push order, which breaks ties between events for
+// the same cycle
+#[derive(Debug, Clone)]
+pub(crate) struct Scheduled {
+    pub serial: usize,
+    pub event: Event,
+}
+
+impl Scheduled {
+    pub fn key(&self) -> (usize, usize) {
+        (self.event.cycle, self.serial)
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BinaryHeapQueue {
+    heap: BinaryHeap<Reverse<Scheduled>>,
+    serial: usize,
+}
+
+impl BinaryHeapQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventQueue for BinaryHeapQueue {
+    fn push(&mut self, event: Event) {
+        self.heap.push(Reverse(Scheduled {
+            serial: self.serial,
+            event,
+        }));
+        self.serial += 1;
+    }
+
+    fn next_cycle(&self) -> Option<usize> {
+        self.heap
+            .peek()
+            .map(|Reverse(scheduled)| scheduled.event.cycle)
+    }
+
+    fn pop_due(&mut self, cycle: usize) -> Option<Event> {
+        if self.next_cycle()? > cycle {
+            return None;
+        }
+
+        self.heap.pop().map(|Reverse(scheduled)| scheduled.event)
+    }
+
+    fn remove(&mut self, predicate: &dyn Fn(&Event) -> bool) -> Option<Event> {
+        let mut scheduled = std::mem::take(&mut self.heap).into_sorted_vec();
+        // sorted_vec is in descending order because of Reverse
+        let position = scheduled
+            .iter()
+            .rposition(|Reverse(scheduled)| predicate(&scheduled.event));
+        let removed = position.map(|position| scheduled.remove(position).0.event);
+        self.heap = scheduled.into();
+
+        removed
+    }
+
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Event> + '_> {
+        let mut scheduled: Vec<&Scheduled> = self
+            .heap
+            .iter()
+            .map(|Reverse(scheduled)| scheduled)
+            .collect();
+        scheduled.sort();
+
+        Box::new(scheduled.into_iter().map(|scheduled| &scheduled.event))
+    }
+
+    fn box_clone(&self) -> Box<dyn EventQueue> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [QueueKind; 3] = [
+        QueueKind::BinaryHeap,
+        QueueKind::Calendar,
+        QueueKind::Ladder,
+    ];
+
+    // what every queue has to agree on: events as (cycle, transition_index,
+    // constant), None where nothing came out
+    type Outcome = Vec<Option<(usize, usize, isize)>>;
+
+    fn key(event: &Event) -> (usize, usize, isize) {
+        (event.cycle, event.transition_index, event.constant)
+    }
+
+    // the same mix of pushes, pops, removals and peeks for any kind, with
+    // events spread over span cycles from the clock; span 1 makes nothing but
+    // ties
+    fn run(kind: QueueKind, seed: u64, span: usize) -> Outcome {
+        let mut seed = seed;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+
+        let mut queue = kind.build();
+        let mut cycle = 0;
+        let mut outcome = vec![];
+        for step in 0..3_000 {
+            match random() % 10 {
+                0..=4 => queue.push(Event {
+                    cycle: cycle + random() % span,
+                    transition_index: step,
+                    constant: (random() % 3) as isize - 1,
+                }),
+                5..=7 => {
+                    let event = queue.pop_due(cycle);
+                    if event.is_none() {
+                        cycle = queue.next_cycle().unwrap_or(cycle);
+                    }
+                    outcome.push(event.as_ref().map(key));
+                }
+                8 => {
+                    let residue = random() % 7;
+                    let event = queue.remove(&|event| event.transition_index % 7 == residue);
+                    outcome.push(event.as_ref().map(key));
+                }
+                _ => {
+                    outcome.extend(queue.iter().map(|event| Some(key(event))));
+                    outcome.push(None);
+                    assert_eq!(queue.len(), queue.iter().count());
+                }
+            }
+        }
+        while let Some(event) = queue.pop_due(usize::MAX) {
+            outcome.push(Some(key(&event)));
+        }
+        assert!(queue.is_empty());
+
+        outcome
+    }
+
+    #[test]
+    fn queues_agree() {
+        for (seed, span) in [(1, 1), (2, 10), (3, 1_000), (4, 1_000_000)] {
+            let heap = run(QueueKind::BinaryHeap, seed, span);
+            for kind in &KINDS[1..] {
+                assert!(
+                    heap == run(*kind, seed, span),
+                    "{kind} differs, span {span}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ties_keep_push_order() {
+        for kind in KINDS {
+            let mut queue = kind.build();
+            for (transition_index, cycle) in [5, 3, 5, 3, 5].into_iter().enumerate() {
+                queue.push(Event {
+                    cycle,
+                    transition_index,
+                    constant: 0,
+                });
+            }
+            queue.remove(&|event| event.transition_index == 2);
+
+            let order: Vec<usize> = std::iter::from_fn(|| queue.pop_due(usize::MAX))
+                .map(|event| event.transition_index)
+                .collect();
+            assert_eq!(order, [1, 3, 0, 4], "{kind}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::polyfill::Lefs;
+use std::{collections::BTreeMap, fmt::Display};
+
+// A model at a glance, what `lefs inspect` prints
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub transitions: usize,
+    pub output_ids: Vec<usize>,
+    // the cycle a simulation of the model starts at
+    pub cycle: usize,
+    // ids of the transitions enabled at that cycle, once immediate events
+    // are applied
+    pub enabled_ids: Vec<usize>,
+    // payloads per transition -> transitions with that many
+    pub fan_out: BTreeMap<usize, usize>,
+    // payloads aimed at a transition -> transitions with that many
+    pub fan_in: BTreeMap<usize, usize>,
+    // ids of the members of every conflict group with more than one, the
+    // only ones whose transitions compete
+    pub conflict_groups: Vec<Vec<usize>>,
+    // ids in other subnets -> ids of the transitions sending them events
+    pub external_ids: BTreeMap<usize, Vec<usize>>,
+    pub events: usize,
+    pub immediate_events: usize,
+    pub external_events: usize,
+}
+
+impl Summary {
+    pub fn new(lefs: &Lefs) -> Self {
+        let transitions = &lefs.transitions;
+        let ids = |indices: &[usize]| indices.iter().map(|&i| transitions[i].id).collect();
+
+        // the same as Engine::new does before the first step
+        let cycle = transitions.iter().map(|t| t.cycle).max().unwrap_or(0);
+        let mut state: Vec<(isize, usize)> =
+            transitions.iter().map(|t| (t.constant, t.cycle)).collect();
+        for event in &lefs.immediate_events {
+            state[event.transition_index].0 += event.constant;
+            state[event.transition_index].1 = event.cycle;
+        }
+        let enabled: Vec<usize> = (0..transitions.len())
+            .filter(|&i| state[i].0 <= 0 && state[i].1 == cycle)
+            .collect();
+
+        let mut fan_out = BTreeMap::new();
+        let mut incoming = vec![0; transitions.len()];
+        let mut external_ids: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for transition in transitions {
+            let payloads = transition.iul_payloads.len()
+                + transition.pul_payloads.len()
+                + transition.external_payloads.len();
+            *fan_out.entry(payloads).or_default() += 1;
+
+            for payload in transition
+                .iul_payloads
+                .iter()
+                .chain(&transition.pul_payloads)
+            {
+                incoming[payload.transition_index] += 1;
+            }
+            for payload in &transition.external_payloads {
+                let senders = external_ids.entry(payload.transition_id).or_default();
+                if !senders.contains(&transition.id) {
+                    senders.push(transition.id);
+                }
+            }
+        }
+        let mut fan_in = BTreeMap::new();
+        for payloads in incoming {
+            *fan_in.entry(payloads).or_default() += 1;
+        }
+
+        Summary {
+            transitions: transitions.len(),
+            output_ids: transitions
+                .iter()
+                .filter(|t| t.output)
+                .map(|t| t.id)
+                .collect(),
+            cycle,
+            enabled_ids: ids(&enabled),
+            fan_out,
+            fan_in,
+            conflict_groups: lefs
+                .conflict_groups
+                .iter()
+                .filter(|group| group.len() > 1)
+                .map(|group| ids(group))
+                .collect(),
+            external_ids,
+            events: lefs.events.len(),
+            immediate_events: lefs.immediate_events.len(),
+            external_events: lefs.external_events.len(),
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let histogram = |f: &mut std::fmt::Formatter<'_>, histogram: &BTreeMap<usize, usize>| {
+            for (payloads, transitions) in histogram {
+                writeln!(f, "  {payloads:>4} payloads: {transitions} transitions")?;
+            }
+            Ok(())
+        };
+
+        writeln!(f, "transitions: {}", self.transitions)?;
+        writeln!(f, "output transitions: {:?}", self.output_ids)?;
+        writeln!(f, "starts at cycle: {}", self.cycle)?;
+        writeln!(f, "enabled: {:?}", self.enabled_ids)?;
+        writeln!(
+            f,
+            "pending events: {} ({} immediate, {} external)",
+            self.events, self.immediate_events, self.external_events
+        )?;
+        writeln!(f, "fan-out:")?;
+        histogram(f, &self.fan_out)?;
+        writeln!(f, "fan-in:")?;
+        histogram(f, &self.fan_in)?;
+        writeln!(
+            f,
+            "competing conflict groups: {}",
+            self.conflict_groups.len()
+        )?;
+        for group in &self.conflict_groups {
+            writeln!(f, "  {group:?}")?;
+        }
+        write!(f, "external references: {}", self.external_ids.len())?;
+        for (id, senders) in &self.external_ids {
+            write!(f, "\n  {id} <- {senders:?}")?;
+        }
+
+        Ok(())
+    }
+}
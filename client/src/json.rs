@@ -1,8 +1,38 @@
 use serde::{Deserialize, Serialize};
 
+// tags the Go tool writes at the top of every model file
+pub const INTERIOR: usize = 1;
+pub const EXTERIOR: usize = 2;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Lefs {
+    #[serde(rename = "INTERIOR", default)]
+    pub interior: usize,
+
+    #[serde(rename = "EXTERIOR", default)]
+    pub exterior: usize,
+
     pub ia_red: Vec<Transition>,
+
+    // number of transitions in ia_red
+    #[serde(default)]
+    pub ii_indice: usize,
+
+    // ii_idglobal of the enabled transitions
+    #[serde(default)]
+    pub is_transicionessensibilizadas: Vec<usize>,
+
+    #[serde(default)]
+    pub il_eventos: Lista<Event>,
+
+    #[serde(default)]
+    pub il_eventosinmediatos: Lista<Event>,
+
+    #[serde(default)]
+    pub il_eventos_exteriores: Lista<Event>,
+
+    #[serde(default)]
+    pub il_grupos_conflicto: Lista<Vec<usize>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,7 +47,47 @@ pub struct Transition {
 
     #[serde(rename = "ii_listactes_PUL")]
     pub ii_listactes_pul: Vec<Payload>,
+
+    #[serde(default)]
+    pub ii_vecesdisparada: usize,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ii_grupoconflicto: Option<usize>,
+
+    #[serde(
+        rename = "iL_tiemposhastamarca",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub il_tiemposhastamarca: Option<Tiempos>,
+
+    #[serde(default)]
+    pub ib_desalida: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Payload(pub usize, pub isize);
+pub struct Lista<T> {
+    pub il_milista: Vec<T>,
+}
+
+impl<T> Default for Lista<T> {
+    fn default() -> Self {
+        Lista { il_milista: vec![] }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tiempos {
+    pub il_tiempos: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Payload(pub isize, pub isize);
+
+// ii_transicion is the ii_idglobal of the transition the event is for
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Event {
+    pub ii_tiempo: usize,
+    pub ii_transicion: usize,
+    pub ii_cte: isize,
+}
@@ -0,0 +1,234 @@
+use crate::{
+    engine::Event,
+    event_queue::{EventQueue, Scheduled},
+};
+use std::collections::VecDeque;
+
+// buckets holding more events than this are split into a new rung rather
+// than sorted
+const THRESHOLD: usize = 50;
+
+// Tang, Goh and Thng's ladder queue. Far future events are dumped unsorted on
+// top, the ladder spreads them over rungs of buckets that get finer the
+// closer they are to the present, and only the bucket about to be dequeued
+// gets sorted into bottom.
+#[derive(Debug, Clone, Default)]
+pub struct LadderQueue {
+    // events at or after top_start, unsorted
+    top: Vec<Scheduled>,
+    top_start: usize,
+    // coarsest rung first, each rung spans a bucket of the one before it
+    rungs: Vec<Rung>,
+    // the earliest events, sorted, every event before bottom_end is here
+    bottom: VecDeque<Scheduled>,
+    bottom_end: usize,
+    len: usize,
+    serial: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Rung {
+    start: usize,
+    // cycles per bucket
+    width: usize,
+    buckets: Vec<Vec<Scheduled>>,
+    // buckets before this one have been handed down already
+    current: usize,
+}
+
+impl Rung {
+    // spreads scheduled over buckets starting at start, about one event per
+    // bucket unless width gets down to a single cycle
+    fn new(start: usize, span: usize, scheduled: Vec<Scheduled>) -> Self {
+        let width = span.div_ceil(scheduled.len().max(1)).max(1);
+        let mut rung = Rung {
+            start,
+            width,
+            buckets: vec![vec![]; span.div_ceil(width).max(1)],
+            current: 0,
+        };
+        for scheduled in scheduled {
+            rung.insert(scheduled);
+        }
+        rung
+    }
+
+    fn covers(&self, cycle: usize) -> bool {
+        cycle >= self.start + self.current * self.width && cycle < self.end()
+    }
+
+    fn end(&self) -> usize {
+        self.start + self.buckets.len() * self.width
+    }
+
+    fn insert(&mut self, scheduled: Scheduled) {
+        let bucket = (scheduled.event.cycle - self.start) / self.width;
+        self.buckets[bucket].push(scheduled);
+    }
+}
+
+impl LadderQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, scheduled: Scheduled) {
+        let cycle = scheduled.event.cycle;
+        if self.bottom.is_empty() || cycle >= self.top_start {
+            self.top.push(scheduled);
+        } else if let Some(rung) = self.rungs.iter_mut().rev().find(|rung| rung.covers(cycle)) {
+            rung.insert(scheduled);
+        } else {
+            // earlier than anything on the ladder
+            let position = self.bottom.partition_point(|pending| *pending < scheduled);
+            self.bottom.insert(position, scheduled);
+
+            // sorted inserts get slow on a long bottom, it goes back on the
+            // ladder as the finest rung, reaching up to where the ladder
+            // carries on so no cycle is left uncovered in between
+            let first = self.bottom.front().unwrap().event.cycle;
+            let last = self.bottom.back().unwrap().event.cycle;
+            if self.bottom.len() > THRESHOLD && first < last {
+                let end = self
+                    .rungs
+                    .last()
+                    .map_or(self.top_start, |rung| {
+                        rung.start + rung.current * rung.width
+                    })
+                    .max(last + 1);
+                let bottom = std::mem::take(&mut self.bottom).into();
+                self.rungs.push(Rung::new(first, end - first, bottom));
+            }
+        }
+    }
+
+    // refills bottom so it always holds the earliest event
+    fn prepare(&mut self) {
+        while self.bottom.is_empty() {
+            let Some(rung) = self.rungs.last_mut() else {
+                if self.top.is_empty() {
+                    return;
+                }
+                let top = std::mem::take(&mut self.top);
+                let start = top.iter().map(|s| s.event.cycle).min().unwrap();
+                let end = top.iter().map(|s| s.event.cycle).max().unwrap() + 1;
+                let rung = Rung::new(start, end - start, top);
+                self.top_start = rung.end();
+                self.rungs.push(rung);
+                continue;
+            };
+
+            let Some(current) =
+                (rung.current..rung.buckets.len()).find(|&bucket| !rung.buckets[bucket].is_empty())
+            else {
+                self.rungs.pop();
+                continue;
+            };
+            rung.current = current + 1;
+            let scheduled = std::mem::take(&mut rung.buckets[current]);
+            let start = rung.start + current * rung.width;
+            let width = rung.width;
+
+            if scheduled.len() > THRESHOLD && width > 1 {
+                self.rungs.push(Rung::new(start, width, scheduled));
+            } else {
+                let mut scheduled = scheduled;
+                scheduled.sort();
+                self.bottom = scheduled.into();
+                self.bottom_end = start + width;
+            }
+        }
+    }
+}
+
+impl EventQueue for LadderQueue {
+    fn push(&mut self, event: Event) {
+        let serial = self.serial;
+        self.serial += 1;
+        self.insert(Scheduled { serial, event });
+        self.len += 1;
+        self.prepare();
+    }
+
+    fn next_cycle(&self) -> Option<usize> {
+        self.bottom.front().map(|scheduled| scheduled.event.cycle)
+    }
+
+    fn pop_due(&mut self, cycle: usize) -> Option<Event> {
+        if self.next_cycle()? > cycle {
+            return None;
+        }
+
+        let scheduled = self.bottom.pop_front()?;
+        self.len -= 1;
+        self.prepare();
+
+        Some(scheduled.event)
+    }
+
+    fn remove(&mut self, predicate: &dyn Fn(&Event) -> bool) -> Option<Event> {
+        // the earliest match is in the earliest part of the queue holding one
+        let removed = if let Some(position) = self
+            .bottom
+            .iter()
+            .position(|scheduled| predicate(&scheduled.event))
+        {
+            self.bottom.remove(position)
+        } else {
+            let buckets = self
+                .rungs
+                .iter_mut()
+                .rev()
+                .flat_map(|rung| rung.buckets[rung.current..].iter_mut())
+                .chain(std::iter::once(&mut self.top));
+            let mut earliest: Option<(&mut Vec<Scheduled>, usize)> = None;
+            for bucket in buckets {
+                let position = bucket
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, scheduled)| predicate(&scheduled.event))
+                    .min_by_key(|(_, scheduled)| scheduled.key())
+                    .map(|(position, _)| position);
+                if let Some(position) = position {
+                    let better = earliest
+                        .as_ref()
+                        .is_none_or(|(best, i)| bucket[position] < best[*i]);
+                    if better {
+                        earliest = Some((bucket, position));
+                    }
+                }
+            }
+            earliest.map(|(bucket, position)| bucket.swap_remove(position))
+        };
+
+        if removed.is_some() {
+            self.len -= 1;
+            self.prepare();
+        }
+        removed.map(|scheduled| scheduled.event)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = &Event> + '_> {
+        let mut scheduled: Vec<&Scheduled> = self
+            .bottom
+            .iter()
+            .chain(
+                self.rungs
+                    .iter()
+                    .flat_map(|rung| rung.buckets.iter().flatten()),
+            )
+            .chain(self.top.iter())
+            .collect();
+        scheduled.sort();
+
+        Box::new(scheduled.into_iter().map(|scheduled| &scheduled.event))
+    }
+
+    fn box_clone(&self) -> Box<dyn EventQueue> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,29 @@
+//! Simulation of timed Petri nets in the LEF format, either on their own or
+//! split in subnets simulated by nodes that exchange events over TCP.
+
+pub mod calendar_queue;
+pub mod chrome_trace;
+pub mod engine;
+pub mod error;
+pub mod event_queue;
+pub mod inspect;
+pub mod json;
+pub mod ladder_queue;
+pub mod message;
+pub mod model;
+pub mod node;
+pub mod polyfill;
+pub mod report;
+pub mod trace;
+pub mod validate;
+
+pub use chrome_trace::ChromeTrace;
+pub use engine::{Checkpoint, ConflictPolicy, Engine, Event, ExternalEvent, Firings, Fossils, Log};
+pub use error::{AppError, Result};
+pub use event_queue::{EventQueue, QueueKind};
+pub use inspect::Summary;
+pub use model::{Model, ModelCache};
+pub use polyfill::{Lefs, Transition};
+pub use report::{SimulationReport, TransitionReport};
+pub use trace::{TraceFormat, TraceWriter};
+pub use validate::{validate, Diagnostic, Problem, Severity};
@@ -1,365 +1,546 @@
-use crate::{engine::Engine, polyfill::Lefs};
-use error::Result;
+use lefs::{
+    message::{self, Job, Message, NodeInfo, HEARTBEAT_INTERVAL},
+    model::MAX_MODEL_SIZE,
+    node::{Node, SyncMode},
+    AppError, ChromeTrace, ConflictPolicy, Engine, Lefs, Model, ModelCache, QueueKind, Result,
+    Severity, SimulationReport, Summary, TraceWriter,
+};
+use std::collections::VecDeque;
 use std::env;
-use std::io::{prelude::*, BufReader};
-use std::net::{TcpListener, TcpStream};
-
-fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let address = &args[1];
-    let server_address = &args[2];
-    let last_cycle = &args[3];
-    let last_cycle = last_cycle.parse::<usize>().unwrap();
-    let listener = TcpListener::bind(address).unwrap();
-
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        handle_connection(stream, last_cycle, address, server_address)?;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info, warn, Event, Level, Subscriber};
+use tracing_subscriber::{
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+// pause between two attempts at reaching a coordinator that isn't up yet
+const REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+
+// lefs <server_address> [options] [-q | -v | -vv], see client
+// lefs validate <model>...
+// lefs inspect <model>...
+// lefs simulate <model>... --last <cycle> [options], see simulate
+//   -q   warnings only
+//   -v   clock moves, rollbacks and GVT rounds
+//   -vv  the whole net every cycle, as client.go dumps it
+fn main() {
+    let (flags, args): (Vec<String>, Vec<String>) = env::args()
+        .skip(1)
+        .partition(|arg| matches!(arg.as_str(), "-q" | "-v" | "-vv"));
+
+    let level = match flags.last().map(String::as_str) {
+        Some("-q") => Level::WARN,
+        Some("-v") => Level::DEBUG,
+        Some("-vv") => Level::TRACE,
+        _ => Level::INFO,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .event_format(Plain)
+        .init();
+
+    let result = match args.first().map(String::as_str) {
+        Some("validate") => validate(&args[1..]),
+        Some("inspect") => inspect(&args[1..]),
+        Some("simulate") => simulate(&args[1..]),
+        _ => client(&args),
+    };
+    if let Err(error) = result {
+        error!("{error}");
+        process::exit(1);
     }
-
-    Ok(())
 }
 
-fn handle_connection(
-    mut stream: TcpStream,
-    last_cycle: usize,
-    address: &str,
-    server_address: &str,
-) -> Result<()> {
-    let buf_reader = BufReader::new(&mut stream);
-    let message: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
-    if let Some(path) = message.get(0) {
-        println!("{path}");
-        let lefs = Lefs::new(path)?;
-        let mut engine = Engine::new(lefs);
-        engine.simulate(0, last_cycle);
-
-        let mut stream = TcpStream::connect(server_address).unwrap();
-        let message = format!("{address} processed petri network {path}\n\n");
-        stream.write_all(message.as_bytes()).unwrap();
+// lists every problem in the models, fails if any can't be simulated
+fn validate(paths: &[String]) -> Result<()> {
+    let mut invalid = false;
+    for path in paths {
+        let lefs = File::open(path)
+            .map_err(AppError::from)
+            .and_then(|file| Ok(serde_json::from_reader(BufReader::new(file))?));
+        let diagnostics = match lefs {
+            Ok(lefs) => lefs::validate(&lefs),
+            Err(error) => {
+                println!("{path}: error: {error}");
+                invalid = true;
+                continue;
+            }
+        };
+
+        for diagnostic in &diagnostics {
+            println!("{path}: {diagnostic}");
+        }
+        invalid |= diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error);
+        if diagnostics.is_empty() {
+            println!("{path}: ok");
+        }
     }
 
+    if invalid {
+        process::exit(1);
+    }
     Ok(())
 }
 
-mod engine {
-    use crate::polyfill::Lefs;
-    use chrono::prelude::*;
-    use std::collections::VecDeque;
-
-    #[derive(Debug)]
-    pub struct Engine {
-        pub cycle: usize,
-        pub lefs: Lefs,
-        pub events: VecDeque<Event>,
-        pub logs: Vec<Log>,
-        pub event_count: usize,
-    }
-
-    impl Engine {
-        pub fn new(lefs: Lefs) -> Self {
-            Engine {
-                cycle: 0,
-                lefs,
-                events: VecDeque::new(),
-                logs: vec![],
-                event_count: 0,
+// summarizes the models, fails if any can't be loaded
+fn inspect(paths: &[String]) -> Result<()> {
+    let mut invalid = false;
+    for path in paths {
+        match Lefs::new(path) {
+            Ok(lefs) => {
+                println!("{path}");
+                println!("{}", Summary::new(&lefs));
             }
-        }
-
-        // SimularPeriodo
-        pub fn simulate(&mut self, first_cycle: usize, last_cycle: usize) {
-            let start = Utc::now();
-            self.cycle = first_cycle;
-
-            for _ in first_cycle..last_cycle {
-                println!("RELOJ LOCAL !!!  = {}", self.cycle);
-                println!("{}", self.lefs);
-                // simularUnpaso
-                self.step(last_cycle);
+            Err(error) => {
+                error!("{error}");
+                invalid = true;
             }
-
-            println!("event_count: {}", self.event_count);
-            let elapsed = Utc::now() - start;
-            println!(
-                "elapsed: {:?} microseconds",
-                elapsed.num_nanoseconds().unwrap() / 1000
-            );
         }
+    }
 
-        // simularUnpaso
-        fn step(&mut self, last_cycle: usize) {
-            // actualizaSensibilizadas
-            self.pep();
-
-            println!("-----------Stack de transiciones sensibilizadas---------");
-            println!("{:?}", self.lefs.estimulated_transition_indices);
-            println!("-----------Final Stack de transiciones---------");
-
-            while let Some(estimulated_transition_index) =
-                self.lefs.estimulated_transition_indices.pop()
-            {
-                self.fire(estimulated_transition_index);
-                self.logs.push(Log {
-                    estimulated_transition_index,
-                    cycle: self.cycle,
-                })
-            }
+    if invalid {
+        process::exit(1);
+    }
+    Ok(())
+}
 
-            println!("-----------Lista eventos después de disparos---------");
-            println!("Estructura EventList");
-            for (i, event) in self.events.iter().enumerate() {
-                println!("  Evento -> {i}");
-                println!("{event}");
+// lefs <server_address> [--listen <address>] [--id <id>]
+//   [--max-model-size <bytes>] [--output-dir <dir>]
+// registers with the coordinator at server_address, and again with every
+// coordinator started there after it, and waits for jobs; the node goes by its host name followed by the port it listens at unless given
+// an id. Only a node listening at a fixed port or given an id is taken for the
+// same one after a restart. Jobs only get to write
+// reports, traces, timelines and snapshots into the output directory, and
+// nowhere without one
+fn client(args: &[String]) -> Result<()> {
+    let invalid = || AppError::InvalidArguments(args.join(" "));
+    let mut server_address = None;
+    // any interface, any port
+    let mut listen = "0.0.0.0:0".to_string();
+    let mut id = None;
+    let mut max_model_size = None;
+    let mut output_dir = None;
+
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        let mut value = || options.next().ok_or_else(invalid);
+        match arg.as_str() {
+            "--listen" => listen = value()?.clone(),
+            "--id" => id = Some(value()?.clone()),
+            "--output-dir" => output_dir = Some(PathBuf::from(value()?)),
+            "--max-model-size" => {
+                max_model_size = Some(value()?.parse().map_err(|_| invalid())?);
             }
-            println!("-----------Final lista eventos---------");
-
-            self.cycle = if let Some(event) = self.events.front() {
-                event.cycle
-            } else {
-                last_cycle
+            option if option.starts_with("--") => return Err(invalid()),
+            address if server_address.is_none() => server_address = Some(address.to_string()),
+            _ => return Err(invalid()),
+        }
+    }
+    let server_address = server_address.ok_or_else(invalid)?;
+
+    let listener = TcpListener::bind(&listen)?;
+    let address = advertised(&listener, &server_address)?;
+    let node = NodeInfo {
+        id: id.unwrap_or_else(|| default_id(address.port())),
+        address: address.to_string(),
+        cores: thread::available_parallelism().map_or(1, usize::from),
+        max_model_size,
+    };
+    let address = node.address.as_str();
+    let server_address = server_address.as_str();
+    let max_model_size = max_model_size.unwrap_or(MAX_MODEL_SIZE);
+
+    // the listener keeps accepting messages from peers while we simulate,
+    // each peer over its own connection
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    warn!("{error}");
+                    continue;
+                }
             };
-            println!("NEXT CLOCK...... : {}", self.cycle);
-
-            self.aftermath();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Err(error) = message::receive(stream, &sender, max_model_size) {
+                    warn!("{error}");
+                }
+            });
         }
-
-        fn pep(&mut self) {
-            for (i, transition) in self.lefs.transitions.iter().enumerate() {
-                if transition.constant <= 0 && transition.cycle == self.cycle {
-                    self.lefs.estimulated_transition_indices.push(i)
+    });
+
+    register(&node, server_address)?;
+
+    // a coordinator that stops hearing from us takes us for dead
+    let heartbeat = Message::Heartbeat {
+        address: address.to_string(),
+    };
+    let coordinator = server_address.to_string();
+    thread::spawn(move || loop {
+        thread::sleep(HEARTBEAT_INTERVAL);
+        if let Err(error) = heartbeat.send(&coordinator) {
+            debug!("{coordinator}: {error}");
+        }
+    });
+
+    // peers may talk to us before the coordinator hands us our job, or while
+    // we wait for its model
+    let mut backlog = vec![];
+    let mut models = ModelCache::default();
+    let mut waiting: Option<Job> = None;
+    let mut leftovers = VecDeque::new();
+    loop {
+        let message = match leftovers.pop_front() {
+            Some(message) => message,
+            None => match receiver.recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            },
+        };
+        let mut job = match message {
+            Message::Shutdown => break,
+            Message::Error { message } => {
+                warn!("{message}");
+                continue;
+            }
+            // the coordinator restarted, no need for us to
+            Message::RegisterAgain => {
+                info!("registering again as {} at {}", node.id, node.address);
+                if let Err(error) = Message::Register(node.clone()).send(server_address) {
+                    warn!("{server_address}: {error}");
                 }
+                continue;
             }
-        }
-
-        // dispararTransicion
-        fn fire(&mut self, estimulated_transition_index: usize) {
-            let transition = self.lefs.transitions[estimulated_transition_index].clone();
-
-            for payload in &transition.iul_payloads {
-                self.lefs.transitions[payload.transition_index].constant += payload.constant;
+            Message::Abort { reason } => {
+                warn!("aborted: {reason}");
+                waiting = None;
+                backlog.clear();
+                continue;
             }
-
-            let cycle = transition.cycle + transition.duration;
-            for payload in &transition.pul_payloads {
-                let event = Event {
-                    cycle,
-                    transition_index: payload.transition_index,
-                    constant: payload.constant,
+            Message::Job(job) if models.get(&job.model).is_some() => job,
+            Message::Job(job) => {
+                let need = Message::Need {
+                    address: address.to_string(),
+                    hash: job.model.clone(),
                 };
-                self.events.push_front(event);
+                need.send(server_address)?;
+                waiting = Some(job);
+                continue;
             }
-        }
-
-        fn aftermath(&mut self) {
-            while let Some(event) = self.events.pop_front() {
-                self.lefs.transitions[event.transition_index].constant += event.constant;
-                self.lefs.transitions[event.transition_index].cycle = event.cycle;
-                self.event_count += 1;
+            // models nobody asked for are ignored, a bad one fails the job
+            // that asked for it
+            Message::Model {
+                hash,
+                encoding,
+                data,
+            } => {
+                let Some(job) = waiting.take_if(|job| job.model == hash) else {
+                    warn!("ignoring model {hash} nobody asked for");
+                    continue;
+                };
+                match Model::decode(&hash, encoding, data, max_model_size) {
+                    Ok(model) => {
+                        models.insert(model);
+                        job
+                    }
+                    Err(error) => {
+                        error!("{}: {error}", job.path);
+                        let failed = Message::JobFailed {
+                            address: address.to_string(),
+                            path: job.path,
+                            error: error.to_string(),
+                        };
+                        failed.send(server_address)?;
+                        continue;
+                    }
+                }
+            }
+            message => {
+                backlog.push(message);
+                continue;
+            }
+        };
+
+        let model = models.get(&job.model).unwrap();
+        let accepted = Message::JobAccepted {
+            address: address.to_string(),
+            path: job.path.clone(),
+        };
+        accepted.send(server_address)?;
+        let backlog = std::mem::take(&mut backlog);
+        // a failed job doesn't take the node down with it, the coordinator
+        // decides what becomes of the run
+        let path = job.path.clone();
+        let result = confine(&mut job, output_dir.as_deref())
+            .and_then(|()| handle_job(job, model, backlog, &receiver, address, server_address));
+        match result {
+            Ok(()) => {}
+            Err(AppError::Aborted(reason)) => warn!("aborted: {reason}"),
+            Err(error) => {
+                error!("{path}: {error}");
+                // a peer we can't reach is the one that failed, not us
+                let culprit = match &error {
+                    AppError::Unreachable { address: peer, .. } if peer != server_address => peer,
+                    _ => address,
+                };
+                let failed = Message::JobFailed {
+                    address: culprit.to_string(),
+                    path,
+                    error: error.to_string(),
+                };
+                failed.send(server_address)?;
             }
         }
+        // whatever is left belongs to the run that just ended, except for what
+        // the coordinator sent for the next one
+        leftovers.extend(receiver.try_iter().filter(|message| {
+            matches!(
+                message,
+                Message::Job(_) | Message::Model { .. } | Message::Shutdown
+            )
+        }));
     }
 
-    #[derive(Debug)]
-    pub struct Event {
-        pub cycle: usize,
-        pub transition_index: usize,
-        pub constant: isize,
-    }
+    Ok(())
+}
 
-    #[derive(Debug)]
-    pub struct Log {
-        pub estimulated_transition_index: usize,
-        pub cycle: usize,
+// where peers reach us: the address we reach the coordinator from, unless
+// the listener is bound to a specific one
+fn advertised(listener: &TcpListener, server_address: &str) -> Result<SocketAddr> {
+    let mut address = listener.local_addr()?;
+    if address.ip().is_unspecified() {
+        // nothing is sent, connecting just picks the interface
+        let socket = UdpSocket::bind(SocketAddr::new(address.ip(), 0))?;
+        socket.connect(server_address)?;
+        address.set_ip(socket.local_addr()?.ip());
     }
+
+    Ok(address)
 }
 
-mod polyfill {
-    use crate::{engine::Event, error::Result};
-    use std::{fmt::Display, fs::File, io::BufReader};
+// the host name and the port we listen at, so that nodes sharing a machine
+// keep apart
+fn default_id(port: u16) -> String {
+    let host = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+
+    format!("{host}:{port}")
+}
 
-    #[derive(Debug)]
-    pub struct Lefs {
-        pub transitions: Vec<Transition>,
-        pub estimulated_transition_indices: Vec<usize>,
+// points the outputs job asks for into output_dir, as long as they're bare
+// file names; peers don't get to pick paths on this machine
+fn confine(job: &mut Job, output_dir: Option<&Path>) -> Result<()> {
+    let names = [
+        job.snapshot.as_mut(),
+        job.trace.as_mut().map(|(_, name)| name),
+        job.timeline.as_mut(),
+        job.report.as_mut(),
+    ];
+    for name in names.into_iter().flatten() {
+        let bare = Path::new(name.as_str()).file_name() == Some(name.as_ref());
+        let path = match output_dir {
+            Some(output_dir) if bare => output_dir.join(name.as_str()),
+            _ => return Err(AppError::OutputNotAllowed(name.clone())),
+        };
+        *name = path.to_string_lossy().into_owned();
     }
 
-    impl Lefs {
-        pub fn new(path: &str) -> Result<Self> {
-            let file = File::open(path)?;
-            let rdr = BufReader::new(file);
-            let lefs: crate::json::Lefs = serde_json::from_reader(rdr)?;
-
-            let transitions = lefs
-                .ia_red
-                .into_iter()
-                .map(|transition| {
-                    let iul_payloads = transition
-                        .ii_listactes_iul
-                        .iter()
-                        .map(|i| Payload {
-                            transition_index: i.0,
-                            constant: i.1,
-                        })
-                        .collect();
-
-                    let pul_payloads = transition
-                        .ii_listactes_pul
-                        .iter()
-                        .map(|i| Payload {
-                            transition_index: i.0,
-                            constant: i.1,
-                        })
-                        .collect();
-
-                    Transition {
-                        id: transition.ii_idglobal,
-                        constant: transition.ii_valor,
-                        cycle: transition.ii_tiempo,
-                        duration: transition.ii_duracion_disparo,
-                        iul_payloads,
-                        pul_payloads,
-                    }
-                })
-                .collect();
-
-            let lefs = Self {
-                transitions,
-                estimulated_transition_indices: vec![],
-            };
+    Ok(())
+}
 
-            Ok(lefs)
+// waits for the coordinator to be up if it isn't yet
+fn register(node: &NodeInfo, server_address: &str) -> Result<()> {
+    info!("registering as {} at {}", node.id, node.address);
+    let register = Message::Register(node.clone());
+    loop {
+        match register.send(server_address) {
+            Err(AppError::Unreachable { error, .. })
+                if error.kind() == ErrorKind::ConnectionRefused =>
+            {
+                debug!("{server_address}: {error}");
+                thread::sleep(REGISTER_INTERVAL);
+            }
+            result => return result,
         }
     }
+}
 
-    #[derive(Debug, Clone)]
-    pub struct Transition {
-        pub id: usize,
-        pub constant: isize,
-        pub cycle: usize,
-        pub duration: usize,
-        // I don't know what iul and pul mean, i stands for immediate
-        pub iul_payloads: Vec<Payload>,
-        pub pul_payloads: Vec<Payload>,
-    }
+fn handle_job(
+    job: Job,
+    model: &Model,
+    backlog: Vec<Message>,
+    inbox: &Receiver<Message>,
+    address: &str,
+    server_address: &str,
+) -> Result<()> {
+    info!("{}", job.path);
+    let (mut engine, subnet) = load(vec![(job.path.clone(), model.lefs()?)], &job)?;
+
+    // a whole net has nobody to wait for
+    let report = if job.peers.is_empty() {
+        engine.simulate(engine.cycle, job.last_cycle)
+    } else {
+        let peers = job.peers.clone();
+        let mut node = Node::new(address, server_address, engine, peers, job.sync, inbox);
+        let report = node.run(backlog, job.last_cycle)?;
+        engine = node.engine;
+        report
+    };
+    wrap_up(engine, &subnet, &report, &job)?;
+
+    let done = Message::Done {
+        address: address.to_string(),
+        event_count: report.event_count,
+        path: job.path,
+    };
+    done.send(server_address)?;
 
-    #[derive(Debug, Clone)]
-    pub struct Payload {
-        pub transition_index: usize,
-        pub constant: isize,
-    }
+    Ok(())
+}
 
-    impl Display for Lefs {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            writeln!(f, "STRUCT LEFS")?;
-            writeln!(f, "\tNº transiciones: {}", self.transitions.len())?;
-            writeln!(f, "------Lista transiciones---------")?;
-            for transition in &self.transitions {
-                writeln!(f, "{}", transition)?;
+// lefs simulate <model>... --last <cycle> [--first <cycle>] [--queue <kind>]
+//   [--conflicts <policy>] [--report <path>] [--trace <format> <path>]
+//   [--timeline <path>] [--snapshot <path>]
+// subnets of the same net are merged back and simulated as a whole
+fn simulate(args: &[String]) -> Result<()> {
+    let invalid = || AppError::InvalidArguments(args.join(" "));
+    let mut paths = vec![];
+    let mut last_cycle = None;
+    let mut job = Job {
+        path: String::new(),
+        model: String::new(),
+        peers: vec![],
+        sync: SyncMode::Unsynchronized,
+        conflicts: ConflictPolicy::FirstByIndex,
+        queue: QueueKind::default(),
+        first_cycle: None,
+        last_cycle: 0,
+        snapshot: None,
+        trace: None,
+        timeline: None,
+        report: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(invalid);
+        match arg.as_str() {
+            "--first" => job.first_cycle = Some(value()?.parse().map_err(|_| invalid())?),
+            "--last" => last_cycle = Some(value()?.parse().map_err(|_| invalid())?),
+            "--queue" => job.queue = value()?.parse()?,
+            "--conflicts" => job.conflicts = value()?.parse()?,
+            "--report" => job.report = Some(value()?.clone()),
+            "--trace" => {
+                let format = value()?.parse()?;
+                job.trace = Some((format, value()?.clone()));
             }
-            writeln!(f, "------Final lista transiciones---------")?;
-            writeln!(f, "FINAL ESTRUCTURA LEFS")
+            "--timeline" => job.timeline = Some(value()?.clone()),
+            "--snapshot" => job.snapshot = Some(value()?.clone()),
+            option if option.starts_with("--") => return Err(invalid()),
+            path => paths.push(path.to_string()),
         }
     }
-
-    impl Display for Transition {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            writeln!(f, "Dato Transicion:")?;
-            writeln!(f, "IDLOCALTRANSICION: {}", self.id)?;
-            writeln!(f, " VALOR LEF: {}", self.constant)?;
-            writeln!(f, " TIEMPO: {}", self.cycle)?;
-            writeln!(f, " DURACION DISPARO: {}", self.duration)?;
-            writeln!(f, " LISTA DE CTES IUL: ")?;
-            for payload in &self.iul_payloads {
-                writeln!(
-                    f,
-                    "\tTRANSICION: {}\t\tCTE: {}",
-                    payload.transition_index, payload.constant
-                )?;
-            }
-            writeln!(f, " LISTA DE CTES PUL: ")?;
-            for payload in &self.pul_payloads {
-                writeln!(
-                    f,
-                    "\tTRANSICION: {}\t\tCTE: {}",
-                    payload.transition_index, payload.constant
-                )?;
-            }
-            Ok(())
-        }
+    if paths.is_empty() {
+        return Err(invalid());
     }
-
-    impl Display for Event {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            writeln!(f, "    TIEMPO: {}", self.cycle)?;
-            writeln!(f, "    TRANSICION: {}", self.transition_index)?;
-            writeln!(f, "    CONSTANTE: {}", self.constant)
-        }
+    job.path = paths.join(" ");
+    job.last_cycle = last_cycle.ok_or_else(invalid)?;
+
+    let mut models = vec![];
+    for path in paths {
+        info!("{path}");
+        let lefs = Lefs::new(&path)?;
+        models.push((path, lefs));
     }
+    let (mut engine, subnet) = load(models, &job)?;
+    let report = engine.simulate(engine.cycle, job.last_cycle);
+    wrap_up(engine, &subnet, &report, &job)
 }
 
-mod json {
-
-    use serde::{Deserialize, Serialize};
-
-    #[derive(Serialize, Deserialize, Debug)]
-    pub struct Lefs {
-        pub ia_red: Vec<Transition>,
+// the engine for models, each one along with its path, set up as job says
+// and with the name it goes by in traces
+fn load(models: Vec<(String, Lefs)>, job: &Job) -> Result<(Engine, String)> {
+    // subnets go by the name of their model file
+    let subnet = models
+        .iter()
+        .map(|(path, _)| {
+            Path::new(path)
+                .file_stem()
+                .map_or(path.into(), |stem| stem.to_string_lossy())
+        })
+        .collect::<Vec<_>>()
+        .join("+");
+
+    let mut subnets: Vec<Lefs> = models.into_iter().map(|(_, lefs)| lefs).collect();
+    let lefs = match subnets.len() {
+        1 => subnets.pop().unwrap(),
+        _ => Lefs::merge(subnets)?,
+    };
+
+    let mut engine = Engine::new(lefs, job.queue).with_conflict_policy(job.conflicts);
+    engine.keep_history = job.timeline.is_some();
+    if let Some(first_cycle) = job.first_cycle {
+        engine.cycle = first_cycle;
     }
-
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct Transition {
-        pub ii_idglobal: usize,
-        pub ii_valor: isize,
-        pub ii_tiempo: usize,
-        pub ii_duracion_disparo: usize,
-
-        #[serde(rename = "ii_listactes_IUL")]
-        pub ii_listactes_iul: Vec<Payload>,
-
-        #[serde(rename = "ii_listactes_PUL")]
-        pub ii_listactes_pul: Vec<Payload>,
+    if let Some((format, trace_path)) = &job.trace {
+        engine = engine.with_trace(TraceWriter::create(*format, &subnet, trace_path)?);
     }
 
-    #[derive(Serialize, Deserialize, Debug, Clone)]
-    pub struct Payload(pub usize, pub isize);
+    Ok((engine, subnet))
 }
-mod error {
-    use std::{error::Error, fmt::Display};
-
-    pub type Result<T> = std::result::Result<T, AppError>;
 
-    #[derive(Debug)]
-    pub enum AppError {
-        Io(std::io::Error),
-        SerdeJson(serde_json::Error),
+// writes whatever job asks for once the run is over
+fn wrap_up(mut engine: Engine, subnet: &str, report: &SimulationReport, job: &Job) -> Result<()> {
+    if let Some(report_path) = &job.report {
+        let file = File::create(report_path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), report)?;
     }
-
-    impl Error for AppError {}
-
-    impl Display for AppError {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            match self {
-                Self::Io(error) => write!(f, "{}", error),
-                Self::SerdeJson(error) => write!(f, "{}", error),
-            }
-        }
+    if let Some(snapshot) = &job.snapshot {
+        engine.save(snapshot)?;
     }
-
-    impl From<std::io::Error> for AppError {
-        fn from(value: std::io::Error) -> Self {
-            AppError::Io(value)
-        }
+    if let Some(timeline) = &job.timeline {
+        ChromeTrace::new(&engine, subnet).save(timeline)?;
+    }
+    if let Some(trace) = engine.trace.take() {
+        trace.finish()?;
     }
 
-    impl From<serde_json::Error> for AppError {
-        fn from(value: serde_json::Error) -> Self {
-            AppError::SerdeJson(value)
-        }
+    Ok(())
+}
+
+// just the message, the way the engine used to print it
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
     }
 }
@@ -0,0 +1,486 @@
+use crate::{
+    engine::{ConflictPolicy, ExternalEvent},
+    error::{AppError, Result},
+    event_queue::QueueKind,
+    model::Encoding,
+    node::SyncMode,
+    trace::TraceFormat,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{prelude::*, ErrorKind},
+    net::TcpStream,
+    sync::{mpsc::Sender, Arc, LazyLock, Mutex, PoisonError},
+    time::Duration,
+};
+
+// bumped whenever Message changes shape
+pub const VERSION: u16 = 6;
+
+// both ends of a connection open with these, followed by the version
+const MAGIC: &[u8; 4] = b"LEFS";
+
+// longer frames are taken for garbage rather than read, but for Model frames,
+// which get this much on top of the largest model the receiver takes
+const MAX_FRAME: u64 = 1 << 20;
+
+// longest either end of a connection waits on the other
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how often clients let the coordinator know they're alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+// A process keeps one connection open to every address it sends to, shared
+// by all its threads. Both ends open it with MAGIC and VERSION and hang up
+// unless the other speaks the same version. Every message is then a bincode
+// frame prefixed by its length in big endian; the receiver replies with an
+// Error frame and hangs up if it can't make sense of one.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    // client -> coordinator: the node is up and wants work, sent again under
+    // the same id after a restart
+    Register(NodeInfo),
+    // client -> coordinator: the node at address is still alive, every
+    // HEARTBEAT_INTERVAL
+    Heartbeat {
+        address: String,
+    },
+    // coordinator -> client
+    Job(Job),
+    // client -> coordinator: the node at address has the model for the job
+    // and is starting on it
+    JobAccepted {
+        address: String,
+        path: String,
+    },
+    // client -> client: transitions simulated by the node at address
+    Owns {
+        address: String,
+        transition_ids: Vec<usize>,
+    },
+    // client -> client: events for transitions simulated by the receiver
+    Events {
+        address: String,
+        events: Vec<ExternalEvent>,
+    },
+    // client -> client: the node at address takes back events it sent
+    AntiEvents {
+        address: String,
+        events: Vec<ExternalEvent>,
+    },
+    // client -> client: the node at address won't send events earlier than
+    // cycle anymore
+    Null {
+        address: String,
+        cycle: usize,
+    },
+    // client -> client: the node at address has joined GVT round
+    // coordinator -> client: GVT round starts
+    Marker {
+        address: String,
+        round: usize,
+    },
+    // client -> coordinator: lowest cycle the node at address saw in round
+    Report {
+        address: String,
+        round: usize,
+        cycle: usize,
+    },
+    // coordinator -> client: no node will ever roll back before cycle
+    Gvt {
+        round: usize,
+        cycle: usize,
+    },
+    // client -> coordinator: the node at address is through with the model at
+    // path
+    Done {
+        address: String,
+        event_count: usize,
+        path: String,
+    },
+    // client -> coordinator: the node at address failed the job, as it found
+    // out itself or as a peer that couldn't reach it did; path is the
+    // model of whoever tells
+    JobFailed {
+        address: String,
+        path: String,
+        error: String,
+    },
+    // client -> coordinator: the node at address lacks the model with hash
+    Need {
+        address: String,
+        hash: String,
+    },
+    // coordinator -> client: the model with hash, encoded as data
+    Model {
+        hash: String,
+        encoding: Encoding,
+        data: Vec<u8>,
+    },
+    // reply to a message that couldn't be read
+    Error {
+        message: String,
+    },
+    // coordinator -> client: drop the job at hand, the run is over
+    Abort {
+        reason: String,
+    },
+    // coordinator -> client: no more jobs are coming
+    Shutdown,
+    // coordinator -> client: a heartbeat came from a node the coordinator
+    // doesn't know, a new one most likely, which has to register again
+    RegisterAgain,
+}
+
+// a client as the coordinator knows it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeInfo {
+    // stays the same across restarts, unlike address maybe
+    pub id: String,
+    // where the node listens for messages
+    pub address: String,
+    pub cores: usize,
+    // largest model file the node takes, in bytes
+    pub max_model_size: Option<usize>,
+}
+
+// simulate the model with hash up to last_cycle together with the nodes at
+// peers, path is just what the coordinator calls the model; the outputs,
+// snapshot, trace, timeline and report, are bare file names, which clients
+// only write to under the directory they were started with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub path: String,
+    pub model: String,
+    pub peers: Vec<String>,
+    pub sync: SyncMode,
+    pub conflicts: ConflictPolicy,
+    pub queue: QueueKind,
+    // where the clock starts, the model's own cycle if None
+    pub first_cycle: Option<usize>,
+    pub last_cycle: usize,
+    // where to save the state reached once the run is over
+    pub snapshot: Option<String>,
+    // where to stream firings to and in which format
+    pub trace: Option<(TraceFormat, String)>,
+    // where to save the timeline of the run as a Chrome trace
+    pub timeline: Option<String>,
+    // where to save the end of run statistics as JSON
+    pub report: Option<String>,
+}
+
+// address -> connection to it, None until the first message or after it broke
+type Connection = Arc<Mutex<Option<TcpStream>>>;
+static CONNECTIONS: LazyLock<Mutex<HashMap<String, Connection>>> = LazyLock::new(Mutex::default);
+
+impl Message {
+    pub fn send(&self, address: &str) -> Result<()> {
+        // only sends to the same address wait on each other
+        let connection = CONNECTIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(address.to_string())
+            .or_default()
+            .clone();
+        let mut connection = connection.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // a peer that restarted or rejected a message has hung up on us
+        let mut stream = match connection.take() {
+            Some(stream) if is_open(&stream)? => stream,
+            _ => connect(address)?,
+        };
+        write_frame(&mut stream, self).map_err(|error| match error {
+            AppError::Io(error) => AppError::Unreachable {
+                address: address.to_string(),
+                error,
+            },
+            error => error,
+        })?;
+        *connection = Some(stream);
+
+        Ok(())
+    }
+}
+
+// passes whatever the peer at the other end of stream sends on to inbox, until
+// either of them hangs up; max_model is the largest model it takes in bytes,
+// 0 for none
+pub fn receive(mut stream: TcpStream, inbox: &Sender<Message>, max_model: usize) -> Result<()> {
+    // a peer that doesn't open with our version gets nothing read
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    handshake(&mut stream)?;
+    stream.set_read_timeout(None)?;
+
+    loop {
+        let message = match read_frame(&mut stream, max_model) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(()),
+            Err(error) => {
+                let reply = Message::Error {
+                    message: error.to_string(),
+                };
+                // the sender may be gone already, nothing to do about it
+                let _ = write_frame(&mut stream, &reply);
+                return Err(error);
+            }
+        };
+        if inbox.send(message).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn connect(address: &str) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(address).map_err(|error| AppError::Unreachable {
+        address: address.to_string(),
+        error,
+    })?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.set_write_timeout(Some(REPLY_TIMEOUT))?;
+    // messages are small and every one of them is waited for
+    stream.set_nodelay(true)?;
+    handshake(&mut stream)?;
+
+    Ok(stream)
+}
+
+// both ends say which version they speak, the connection is only any good if
+// it's the same
+fn handshake(stream: &mut (impl Read + Write)) -> Result<()> {
+    let mut preamble = [0; 6];
+    preamble[..4].copy_from_slice(MAGIC);
+    preamble[4..].copy_from_slice(&VERSION.to_be_bytes());
+    stream.write_all(&preamble)?;
+
+    read_exact(stream, &mut preamble)?;
+    check_preamble(&preamble)
+}
+
+fn check_preamble(preamble: &[u8; 6]) -> Result<()> {
+    if &preamble[..4] != MAGIC {
+        return Err(AppError::InvalidMessage(format!("{:?}", &preamble[..4])));
+    }
+    let version = u16::from_be_bytes([preamble[4], preamble[5]]);
+    if version != VERSION {
+        return Err(AppError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+// false if the receiver hung up on stream; receivers only ever write to a
+// connection to say why they're closing it
+fn is_open(mut stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let peeked = stream.peek(&mut [0]);
+    stream.set_nonblocking(false)?;
+    match peeked {
+        Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(true),
+        Ok(0) | Err(_) => Ok(false),
+        Ok(_) => match read_frame(&mut stream, 0)? {
+            Some(Message::Error { message }) => Err(AppError::Rejected(message)),
+            message => Err(AppError::InvalidMessage(format!("{message:?}"))),
+        },
+    }
+}
+
+fn write_frame(stream: &mut impl Write, message: &Message) -> Result<()> {
+    let payload = bincode::serialize(message).map_err(invalid)?;
+    let length = u32::try_from(payload.len())
+        .map_err(|_| AppError::InvalidMessage(format!("{} bytes frame", payload.len())))?;
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    stream.write_all(&frame)?;
+
+    Ok(())
+}
+
+// the next message on stream, None if the sender hung up in between two; a
+// Model frame may carry up to max_model bytes of model
+fn read_frame(stream: &mut impl Read, max_model: usize) -> Result<Option<Message>> {
+    let mut length = [0; 4];
+    let read = stream.read(&mut length)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    read_exact(stream, &mut length[read..])?;
+    let length = u64::from(u32::from_be_bytes(length));
+
+    // bincode starts a message with the index of its variant
+    let mut payload = vec![];
+    if length > MAX_FRAME {
+        let mut variant = [0; 4];
+        read_exact(stream, &mut variant)?;
+        if variant != *MODEL_VARIANT || length > (max_model as u64).saturating_add(MAX_FRAME) {
+            return Err(AppError::InvalidMessage(format!("{length} bytes frame")));
+        }
+        payload.extend_from_slice(&variant);
+    }
+
+    // read as it comes rather than allocated upfront on the sender's word
+    let rest = length - payload.len() as u64;
+    stream.take(rest).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < length {
+        return Err(AppError::InvalidMessage("truncated frame".to_string()));
+    }
+    bincode::deserialize(&payload).map(Some).map_err(invalid)
+}
+
+// how Model frames start
+static MODEL_VARIANT: LazyLock<[u8; 4]> = LazyLock::new(|| {
+    let model = Message::Model {
+        hash: String::new(),
+        encoding: Encoding::Plain,
+        data: vec![],
+    };
+    let payload = bincode::serialize(&model).unwrap();
+    [payload[0], payload[1], payload[2], payload[3]]
+});
+
+// a connection cut short is a malformed message rather than an io problem
+fn read_exact(stream: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    stream.read_exact(buf).map_err(|error| match error.kind() {
+        ErrorKind::UnexpectedEof => AppError::InvalidMessage("truncated frame".to_string()),
+        _ => error.into(),
+    })
+}
+
+fn invalid(error: bincode::Error) -> AppError {
+    AppError::InvalidMessage(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, sync::mpsc, thread};
+
+    fn frame(message: &Message) -> Vec<u8> {
+        let mut frame = vec![];
+        write_frame(&mut frame, message).unwrap();
+        frame
+    }
+
+    fn null() -> Message {
+        Message::Null {
+            address: "127.0.0.1:8000".to_string(),
+            cycle: 42,
+        }
+    }
+
+    #[test]
+    fn preamble() {
+        let mut preamble = *b"LEFS\0\0";
+        preamble[4..].copy_from_slice(&VERSION.to_be_bytes());
+        assert!(check_preamble(&preamble).is_ok());
+
+        let bad_magic = *b"HTTP\0\0";
+        assert!(matches!(
+            check_preamble(&bad_magic),
+            Err(AppError::InvalidMessage(_))
+        ));
+
+        preamble[4..].copy_from_slice(&(VERSION + 1).to_be_bytes());
+        assert!(matches!(
+            check_preamble(&preamble),
+            Err(AppError::UnsupportedVersion(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn frames() {
+        let frame = frame(&null());
+        assert!(matches!(
+            read_frame(&mut frame.as_slice(), 0),
+            Ok(Some(Message::Null { cycle: 42, .. }))
+        ));
+        // hanging up in between two messages is fine, halfway through one isn't
+        assert!(matches!(read_frame(&mut [].as_slice(), 0), Ok(None)));
+        for length in [2, 4, frame.len() - 1] {
+            assert!(matches!(
+                read_frame(&mut &frame[..length], 0),
+                Err(AppError::InvalidMessage(message)) if message == "truncated frame"
+            ));
+        }
+
+        let garbage = [0, 0, 0, 2, 0xff, 0xff];
+        assert!(matches!(
+            read_frame(&mut garbage.as_slice(), 0),
+            Err(AppError::InvalidMessage(_))
+        ));
+    }
+
+    fn model(size: usize) -> Message {
+        Message::Model {
+            hash: "0".repeat(64),
+            encoding: Encoding::Plain,
+            data: vec![0; size],
+        }
+    }
+
+    #[test]
+    fn frame_limit() {
+        // anything but a model is turned down before its payload is read
+        let mut oversized = ((MAX_FRAME + 1) as u32).to_be_bytes().to_vec();
+        oversized.extend_from_slice(&frame(&null())[4..]);
+        assert!(matches!(
+            read_frame(&mut oversized.as_slice(), usize::MAX),
+            Err(AppError::InvalidMessage(message)) if message == format!("{} bytes frame", MAX_FRAME + 1)
+        ));
+
+        // models may be as large as the receiver takes
+        let size = 4 * MAX_FRAME as usize;
+        let large = frame(&model(size));
+        assert!(matches!(
+            read_frame(&mut large.as_slice(), size),
+            Ok(Some(Message::Model { data, .. })) if data.len() == size
+        ));
+        assert!(matches!(
+            read_frame(&mut large.as_slice(), size / 2),
+            Err(AppError::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            read_frame(&mut large.as_slice(), 0),
+            Err(AppError::InvalidMessage(_))
+        ));
+        assert!(matches!(
+            read_frame(&mut &large[..large.len() - 1], size),
+            Err(AppError::InvalidMessage(message)) if message == "truncated frame"
+        ));
+
+        // a frame claiming more than it carries is read as far as it goes
+        let mut short = u32::MAX.to_be_bytes().to_vec();
+        short.extend_from_slice(&frame(&model(8))[4..]);
+        assert!(matches!(
+            read_frame(&mut short.as_slice(), usize::MAX),
+            Err(AppError::InvalidMessage(message)) if message == "truncated frame"
+        ));
+    }
+
+    #[test]
+    fn one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        let accepted = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            receive(stream, &sender, 0)
+        });
+
+        for _ in 0..3 {
+            null().send(&address).unwrap();
+        }
+        for _ in 0..3 {
+            assert!(matches!(
+                receiver.recv(),
+                Ok(Message::Null { cycle: 42, .. })
+            ));
+        }
+
+        // the listener only ever accepted one connection
+        CONNECTIONS.lock().unwrap().remove(&address);
+        assert!(accepted.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    error::{AppError, Result},
+    polyfill::Lefs,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+// A model file as the coordinator hands it out, known by the SHA-256 of its
+// contents so clients can tell whether they already have it
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub hash: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Model {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Model {
+            hash: hash(&bytes),
+            bytes,
+        }
+    }
+
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Self::new(fs::read(path)?))
+    }
+
+    pub fn lefs(&self) -> Result<Lefs> {
+        Lefs::from_reader(self.bytes.as_slice())
+    }
+
+    // the bytes to send, compressed unless that makes them larger
+    pub fn encode(&self) -> Result<(Encoding, Vec<u8>)> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&self.bytes)?;
+        let compressed = encoder.finish()?;
+
+        if compressed.len() < self.bytes.len() {
+            Ok((Encoding::Gzip, compressed))
+        } else {
+            Ok((Encoding::Plain, self.bytes.clone()))
+        }
+    }
+
+    // the model that was encoded into data, as long as its hash checks out
+    // and it's no larger than limit bytes once decompressed
+    pub fn decode(expected: &str, encoding: Encoding, data: Vec<u8>, limit: usize) -> Result<Self> {
+        let bytes = match encoding {
+            Encoding::Plain => data,
+            Encoding::Gzip => {
+                // a byte past the limit is enough to tell
+                let mut bytes = vec![];
+                GzDecoder::new(data.as_slice())
+                    .take(limit as u64 + 1)
+                    .read_to_end(&mut bytes)?;
+                bytes
+            }
+        };
+        if bytes.len() > limit {
+            return Err(AppError::ModelTooLarge { limit });
+        }
+
+        let model = Self::new(bytes);
+        if model.hash != expected {
+            return Err(AppError::HashMismatch {
+                expected: expected.to_string(),
+                actual: model.hash,
+            });
+        }
+        Ok(model)
+    }
+}
+
+// what clients take when they weren't told a limit
+pub const MAX_MODEL_SIZE: usize = 1 << 30;
+
+pub fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Plain,
+    Gzip,
+}
+
+impl FromStr for Encoding {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "plain" => Ok(Encoding::Plain),
+            "gzip" => Ok(Encoding::Gzip),
+            _ => Err(AppError::UnknownValue {
+                setting: "encoding",
+                value: s.to_string(),
+                expected: "plain or gzip",
+            }),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::Plain => write!(f, "plain"),
+            Encoding::Gzip => write!(f, "gzip"),
+        }
+    }
+}
+
+// models a client was sent so far, by hash
+#[derive(Debug, Default)]
+pub struct ModelCache {
+    models: HashMap<String, Model>,
+}
+
+impl ModelCache {
+    pub fn get(&self, hash: &str) -> Option<&Model> {
+        self.models.get(hash)
+    }
+
+    pub fn insert(&mut self, model: Model) {
+        self.models.insert(model.hash.clone(), model);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> Model {
+        Model::open("3subredes.subred0.json").unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let model = model();
+        let (encoding, data) = model.encode().unwrap();
+        assert_eq!(encoding, Encoding::Gzip);
+        let decoded = Model::decode(&model.hash, encoding, data, model.bytes.len()).unwrap();
+        assert_eq!(decoded.bytes, model.bytes);
+    }
+
+    #[test]
+    fn too_large() {
+        let model = model();
+        let limit = model.bytes.len() - 1;
+        for encoding in [Encoding::Plain, Encoding::Gzip] {
+            let data = match encoding {
+                Encoding::Plain => model.bytes.clone(),
+                Encoding::Gzip => model.encode().unwrap().1,
+            };
+            let error = Model::decode(&model.hash, encoding, data, limit).unwrap_err();
+            assert!(matches!(error, AppError::ModelTooLarge { limit: l } if l == limit));
+        }
+    }
+
+    #[test]
+    fn hash_mismatch() {
+        let model = model();
+        let expected = hash(b"another model");
+        let (encoding, data) = model.encode().unwrap();
+        let error = Model::decode(&expected, encoding, data, MAX_MODEL_SIZE).unwrap_err();
+        assert!(matches!(
+            error,
+            AppError::HashMismatch { expected: e, actual } if e == expected && actual == model.hash
+        ));
+    }
+}
@@ -0,0 +1,664 @@
+use crate::{
+    engine::{Checkpoint, Engine, ExternalEvent},
+    error::{AppError, Result},
+    message::Message,
+    report::SimulationReport,
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::mpsc::Receiver,
+};
+use tracing::{debug, debug_span, trace, warn};
+
+// How the clocks of the nodes taking part in a run are kept in step
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SyncMode {
+    // every node runs as fast as it can, events arriving for cycles already
+    // simulated are applied on the next one
+    Unsynchronized,
+    // Chandy-Misra-Bryant: a node only advances to cycles its peers have
+    // promised not to send events for, promises travel as null messages
+    Conservative,
+    // Time Warp: a node simulates ahead as far as it can and rolls back when
+    // an event arrives for a cycle it has already simulated
+    Optimistic,
+}
+
+impl FromStr for SyncMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "unsynchronized" => Ok(SyncMode::Unsynchronized),
+            "conservative" => Ok(SyncMode::Conservative),
+            "optimistic" => Ok(SyncMode::Optimistic),
+            _ => Err(format!("unknown sync mode {s}")),
+        }
+    }
+}
+
+impl Display for SyncMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncMode::Unsynchronized => write!(f, "unsynchronized"),
+            SyncMode::Conservative => write!(f, "conservative"),
+            SyncMode::Optimistic => write!(f, "optimistic"),
+        }
+    }
+}
+
+// Simulates one subnet of a partitioned net, exchanging the events aimed at
+// other subnets with the peers simulating them.
+//
+// Each node sends from a single thread over one connection per peer, so
+// messages between two nodes arrive in the order they were sent.
+//
+// Whatever the sync mode, the run is over once the coordinator announces a
+// GVT past the last cycle: no node has anything left to simulate before it
+// and no event for it is on its way.
+pub struct Node<'a> {
+    pub address: String,
+    pub coordinator: String,
+    pub engine: Engine,
+    pub peers: Vec<String>,
+    pub sync: SyncMode,
+    // ii_idglobal -> address of the node simulating that transition
+    pub routes: HashMap<usize, String>,
+    // last cycle simulated, None before the first one
+    pub processed: Option<usize>,
+    pub gvt: usize,
+    // end of the run, fossils are only collected up to it
+    last_cycle: usize,
+    // peer -> cycle below which it won't send us any more events
+    pub clocks: HashMap<String, usize>,
+    // peer -> last cycle we promised it
+    promises: HashMap<String, usize>,
+    announced: HashSet<String>,
+    // latest GVT round joined, and the one being joined right now
+    joined: usize,
+    round: Option<Round>,
+    warp: TimeWarp,
+    inbox: &'a Receiver<Message>,
+}
+
+// Time Warp bookkeeping, only used in optimistic mode
+#[derive(Default)]
+struct TimeWarp {
+    // state right before simulating each cycle at or above gvt
+    saved: Vec<Saved>,
+    // events we sent at or above gvt, in case we have to take them back
+    sent: Vec<Sent>,
+    // events peers sent us, replayed after a rollback
+    received: Vec<Received>,
+    serial: usize,
+}
+
+struct Saved {
+    cycle: usize,
+    processed: Option<usize>,
+    // events received from here on aren't part of the checkpoint
+    serial: usize,
+    checkpoint: Checkpoint,
+}
+
+struct Sent {
+    cycle: usize,
+    address: String,
+    event: ExternalEvent,
+}
+
+struct Received {
+    serial: usize,
+    event: ExternalEvent,
+}
+
+// A GVT round takes a consistent snapshot of the run: each node records its
+// lowest pending cycle when joining, then keeps lowering it with the events
+// peers sent before joining themselves, which is known once their marker
+// arrives
+struct Round {
+    id: usize,
+    cycle: usize,
+    waiting: HashSet<String>,
+}
+
+impl<'a> Node<'a> {
+    pub fn new(
+        address: &str,
+        coordinator: &str,
+        engine: Engine,
+        peers: Vec<String>,
+        sync: SyncMode,
+        inbox: &'a Receiver<Message>,
+    ) -> Self {
+        let clocks = peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        Node {
+            address: address.to_string(),
+            coordinator: coordinator.to_string(),
+            engine,
+            peers,
+            sync,
+            routes: HashMap::new(),
+            processed: None,
+            gvt: 0,
+            clocks,
+            promises: HashMap::new(),
+            announced: HashSet::new(),
+            joined: 0,
+            round: None,
+            warp: TimeWarp::default(),
+            last_cycle: usize::MAX,
+            inbox,
+        }
+    }
+
+    // backlog holds the messages that arrived before the job did
+    pub fn run(&mut self, backlog: Vec<Message>, last_cycle: usize) -> Result<SimulationReport> {
+        self.last_cycle = last_cycle;
+        let owns = Message::Owns {
+            address: self.address.clone(),
+            transition_ids: self.engine.lefs.transitions.iter().map(|t| t.id).collect(),
+        };
+        for peer in &self.peers {
+            owns.send(peer)?;
+        }
+
+        for message in backlog {
+            self.handle(message)?;
+        }
+
+        // nothing can be sent until we know who simulates each transition
+        while self.announced.len() < self.peers.len() {
+            let message = self.inbox.recv().map_err(|_| AppError::ListenerGone)?;
+            self.handle(message)?;
+        }
+
+        let start = Utc::now();
+        let first_cycle = self.engine.cycle;
+        let lookaheads = self.lookaheads();
+
+        loop {
+            while let Ok(message) = self.inbox.try_recv() {
+                self.handle(message)?;
+            }
+            if self.gvt >= last_cycle {
+                break;
+            }
+
+            let next = self.next_cycle();
+            if self.sync == SyncMode::Conservative {
+                self.promise(next, &lookaheads)?;
+            }
+
+            match next {
+                Some(cycle) if cycle < last_cycle && self.is_safe(cycle) => self.simulate(cycle)?,
+                // nothing to simulate for now, but peers may still send us
+                // events and only GVT tells when the run is over
+                _ => {
+                    let message = self.inbox.recv().map_err(|_| AppError::ListenerGone)?;
+                    self.handle(message)?;
+                }
+            }
+        }
+
+        Ok(self.engine.summarize(start, first_cycle, last_cycle))
+    }
+
+    fn next_cycle(&self) -> Option<usize> {
+        match self.processed {
+            Some(_) => self.engine.next_cycle(),
+            None => Some(self.engine.cycle),
+        }
+    }
+
+    // only cycles every peer has promised to stay away from are safe in
+    // conservative mode, an event for them could still be on its way otherwise
+    fn is_safe(&self, cycle: usize) -> bool {
+        self.sync != SyncMode::Conservative || cycle < self.horizon()
+    }
+
+    fn simulate(&mut self, cycle: usize) -> Result<()> {
+        if self.sync == SyncMode::Optimistic {
+            self.warp.saved.push(Saved {
+                cycle,
+                processed: self.processed,
+                serial: self.warp.serial,
+                checkpoint: self.engine.checkpoint(),
+            });
+        }
+
+        let _cycle = debug_span!("cycle", cycle).entered();
+        self.engine.advance_to(cycle);
+        trace!("RELOJ LOCAL !!!  = {}", self.engine.cycle);
+        trace!("{}", self.engine.lefs);
+
+        self.engine.fire_estimulated();
+        self.send_external_events()?;
+        self.processed = Some(cycle);
+
+        Ok(())
+    }
+
+    fn join_round(&mut self, id: usize) -> Result<()> {
+        if id <= self.joined {
+            return Ok(());
+        }
+
+        self.joined = id;
+        self.round = Some(Round {
+            id,
+            cycle: self.next_cycle().unwrap_or(usize::MAX),
+            waiting: self.peers.iter().cloned().collect(),
+        });
+
+        let marker = Message::Marker {
+            address: self.address.clone(),
+            round: id,
+        };
+        for peer in &self.peers {
+            marker.send(peer)?;
+        }
+
+        Ok(())
+    }
+
+    // an event or anti-event from address with the given cycle has arrived
+    fn observe(&mut self, address: &str, cycle: usize) {
+        if let Some(round) = &mut self.round {
+            if round.waiting.contains(address) {
+                round.cycle = round.cycle.min(cycle);
+            }
+        }
+    }
+
+    // reports our part of the snapshot once every peer has joined the round
+    fn end_round(&mut self) -> Result<()> {
+        if let Some(round) = self.round.take_if(|round| round.waiting.is_empty()) {
+            let report = Message::Report {
+                address: self.address.clone(),
+                round: round.id,
+                cycle: round.cycle,
+            };
+            report.send(&self.coordinator)?;
+        }
+
+        Ok(())
+    }
+
+    // nothing below gvt can be rolled back anymore
+    fn collect_fossils(&mut self, gvt: usize) {
+        debug!("GVT...... : {gvt}");
+        self.gvt = self.gvt.max(gvt);
+        let gvt = self.gvt;
+        self.warp.saved.retain(|saved| saved.cycle >= gvt);
+        self.warp.sent.retain(|sent| sent.cycle >= gvt);
+        self.warp
+            .received
+            .retain(|received| received.event.cycle >= gvt);
+        // the report clips firings at the end of the run, totals can't be
+        self.engine.collect_fossils(gvt.min(self.last_cycle));
+    }
+
+    fn receive(&mut self, mut event: ExternalEvent) -> Result<()> {
+        let late = self.processed.filter(|&cycle| event.cycle <= cycle);
+
+        match self.sync {
+            SyncMode::Unsynchronized => {
+                if let Some(cycle) = late {
+                    event.cycle = cycle + 1;
+                }
+                self.engine.schedule(event)
+            }
+            SyncMode::Conservative => self.engine.schedule(event),
+            SyncMode::Optimistic => {
+                self.warp.received.push(Received {
+                    serial: self.warp.serial,
+                    event: event.clone(),
+                });
+                self.warp.serial += 1;
+
+                // a straggler, replayed by the rollback along with everything
+                // that arrived after the restored checkpoint
+                if late.is_some() {
+                    self.rollback(event.cycle)?;
+                    Ok(())
+                } else {
+                    self.engine.schedule(event)
+                }
+            }
+        }
+    }
+
+    fn cancel(&mut self, event: ExternalEvent) -> Result<()> {
+        let Some(position) = self.warp.received.iter().position(|received| {
+            received.event.transition_id == event.transition_id
+                && received.event.cycle == event.cycle
+                && received.event.constant == event.constant
+        }) else {
+            warn!("nothing to cancel for transition {}", event.transition_id);
+            return Ok(());
+        };
+        let received = self.warp.received.remove(position);
+
+        // already simulated, undo it first
+        if !self.engine.unschedule(&event) {
+            let serial = self.rollback(event.cycle)?;
+            if serial.is_some_and(|serial| received.serial < serial) {
+                self.engine.unschedule(&event);
+            }
+        }
+
+        Ok(())
+    }
+
+    // brings the engine back to right before simulating the first cycle at or
+    // above cycle, returns the serial of the restored checkpoint
+    fn rollback(&mut self, cycle: usize) -> Result<Option<usize>> {
+        let Some(position) = self
+            .warp
+            .saved
+            .iter()
+            .position(|saved| saved.cycle >= cycle)
+        else {
+            return Ok(None);
+        };
+        let saved = self.warp.saved.drain(position..).next().unwrap();
+
+        debug!("ROLLBACK...... : {} -> {}", self.engine.cycle, saved.cycle);
+        self.engine.rollback(saved.checkpoint);
+        self.processed = saved.processed;
+
+        for received in &self.warp.received {
+            if received.serial >= saved.serial {
+                self.engine.schedule(received.event.clone())?;
+            }
+        }
+
+        // whatever we sent while simulating those cycles never happened
+        let (void, sent) = self
+            .warp
+            .sent
+            .drain(..)
+            .partition(|sent| sent.cycle >= saved.cycle);
+        self.warp.sent = sent;
+
+        let mut outgoing: HashMap<String, Vec<_>> = HashMap::new();
+        for Sent { address, event, .. } in void {
+            outgoing.entry(address).or_default().push(event);
+        }
+        for (address, events) in outgoing {
+            let message = Message::AntiEvents {
+                address: self.address.clone(),
+                events,
+            };
+            message.send(&address)?;
+        }
+
+        Ok(Some(saved.serial))
+    }
+
+    // peer -> how far ahead of our next firing the events we send it land,
+    // peers we never send events to get an unbounded lookahead
+    fn lookaheads(&self) -> HashMap<String, usize> {
+        let mut lookaheads: HashMap<String, usize> = self
+            .peers
+            .iter()
+            .map(|peer| (peer.clone(), usize::MAX))
+            .collect();
+
+        for transition in &self.engine.lefs.transitions {
+            for payload in &transition.external_payloads {
+                if let Some(lookahead) = self
+                    .routes
+                    .get(&payload.transition_id)
+                    .and_then(|address| lookaheads.get_mut(address))
+                {
+                    *lookahead = (*lookahead).min(transition.lookahead());
+                }
+            }
+        }
+
+        lookaheads
+    }
+
+    fn horizon(&self) -> usize {
+        self.clocks.values().copied().min().unwrap_or(usize::MAX)
+    }
+
+    // null messages: no transition here can fire before the next cycle we have
+    // to simulate or before a peer's event could reach us, whichever comes first
+    fn promise(&mut self, next: Option<usize>, lookaheads: &HashMap<String, usize>) -> Result<()> {
+        let bound = next.unwrap_or(usize::MAX).min(self.horizon());
+
+        for (peer, lookahead) in lookaheads {
+            let cycle = bound.saturating_add(*lookahead);
+            if self
+                .promises
+                .get(peer)
+                .is_none_or(|&promised| promised < cycle)
+            {
+                let null = Message::Null {
+                    address: self.address.clone(),
+                    cycle,
+                };
+                null.send(peer)?;
+                self.promises.insert(peer.clone(), cycle);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Owns {
+                address,
+                transition_ids,
+            } => {
+                for transition_id in transition_ids {
+                    self.routes.insert(transition_id, address.clone());
+                }
+                self.announced.insert(address);
+            }
+            Message::Events { address, events } => {
+                for event in events {
+                    self.observe(&address, event.cycle);
+                    self.receive(event)?;
+                }
+            }
+            Message::AntiEvents { address, events } => {
+                for event in events {
+                    self.observe(&address, event.cycle);
+                    self.cancel(event)?;
+                }
+            }
+            Message::Null { address, cycle } => {
+                let clock = self.clocks.entry(address).or_insert(0);
+                *clock = (*clock).max(cycle);
+            }
+            Message::Marker { address, round } => {
+                self.join_round(round)?;
+                if let Some(joined) = self.round.as_mut().filter(|joined| joined.id == round) {
+                    joined.waiting.remove(&address);
+                }
+                self.end_round()?;
+            }
+            Message::Gvt { cycle, .. } => self.collect_fossils(cycle),
+            Message::Report { address, .. }
+            | Message::Done { address, .. }
+            | Message::JobFailed { address, .. }
+            | Message::Need { address, .. }
+            | Message::JobAccepted { address, .. }
+            | Message::Heartbeat { address } => {
+                warn!("ignoring coordinator notice from {address}");
+            }
+            Message::Register(node) => {
+                warn!("ignoring registration of {} at {}", node.id, node.address);
+            }
+            Message::Model { hash, .. } => {
+                warn!("ignoring model {hash} while simulating");
+            }
+            Message::Error { message } => warn!("{message}"),
+            Message::Abort { reason } => return Err(AppError::Aborted(reason)),
+            // whoever handed out the job is gone
+            Message::RegisterAgain => {
+                let reason = "the coordinator lost track of us".to_string();
+                return Err(AppError::Aborted(reason));
+            }
+            Message::Shutdown => warn!("ignoring shutdown while simulating"),
+            Message::Job(job) => {
+                warn!("ignoring job {} while simulating", job.path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_external_events(&mut self) -> Result<()> {
+        let mut outgoing: HashMap<&str, Vec<_>> = HashMap::new();
+        for event in self.engine.external_events.drain(..) {
+            let Some(address) = self.routes.get(&event.transition_id) else {
+                warn!("no peer simulates transition {}", event.transition_id);
+                continue;
+            };
+
+            if self.sync == SyncMode::Optimistic {
+                self.warp.sent.push(Sent {
+                    cycle: self.engine.cycle,
+                    address: address.clone(),
+                    event: event.clone(),
+                });
+            }
+            outgoing.entry(address).or_default().push(event);
+        }
+
+        for (address, events) in outgoing {
+            let message = Message::Events {
+                address: self.address.clone(),
+                events,
+            };
+            message.send(address)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{event_queue::QueueKind, message, polyfill::Lefs};
+    use std::{net::TcpListener, sync::mpsc, thread};
+
+    const LAST_CYCLE: usize = 1000;
+
+    fn subnets() -> Vec<Lefs> {
+        (0..3)
+            .map(|i| {
+                let path = format!("{}/3subredes.subred{i}.json", env!("CARGO_MANIFEST_DIR"));
+                Lefs::new(&path).unwrap()
+            })
+            .collect()
+    }
+
+    // ii_idglobal -> times the transition fired and what the report makes of
+    // its firings
+    fn outcome(engine: &Engine, report: &SimulationReport) -> HashMap<usize, (usize, String)> {
+        let transitions = engine.lefs.transitions.iter().zip(&report.transitions);
+        let outcome = transitions.map(|(t, report)| (t.id, (t.fire_count, format!("{report:?}"))));
+        outcome.collect()
+    }
+
+    // an address whose messages end up in the receiver
+    fn listen() -> (String, Receiver<Message>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                thread::spawn(move || message::receive(stream, &sender, 0));
+            }
+        });
+
+        (address, receiver)
+    }
+
+    // every subnet on a node of its own, with GVT rounds run the way the
+    // coordinator runs them
+    fn distributed(sync: SyncMode) -> HashMap<usize, (usize, String)> {
+        let (coordinator, reports) = listen();
+        let nodes: Vec<_> = subnets().into_iter().map(|lefs| (listen(), lefs)).collect();
+        let addresses: Vec<String> = nodes
+            .iter()
+            .map(|((address, _), _)| address.clone())
+            .collect();
+
+        let handles: Vec<_> = nodes
+            .into_iter()
+            .map(|((address, inbox), lefs)| {
+                let peers = addresses
+                    .iter()
+                    .filter(|peer| **peer != address)
+                    .cloned()
+                    .collect();
+                let coordinator = coordinator.clone();
+                thread::spawn(move || {
+                    let engine = Engine::new(lefs, QueueKind::default());
+                    let mut node = Node::new(&address, &coordinator, engine, peers, sync, &inbox);
+                    let report = node.run(vec![], LAST_CYCLE).unwrap();
+                    // GVT rounds leave only the latest logs behind
+                    assert!(node.engine.fossils.log_count > node.engine.logs.len());
+                    outcome(&node.engine, &report)
+                })
+            })
+            .collect();
+
+        for round in 1.. {
+            let marker = Message::Marker {
+                address: coordinator.clone(),
+                round,
+            };
+            for address in &addresses {
+                marker.send(address).unwrap();
+            }
+            let mut gvt = usize::MAX;
+            for _ in &addresses {
+                match reports.recv().unwrap() {
+                    Message::Report { cycle, .. } => gvt = gvt.min(cycle),
+                    message => panic!("{message:?}"),
+                }
+            }
+            let message = Message::Gvt { round, cycle: gvt };
+            for address in &addresses {
+                message.send(address).unwrap();
+            }
+            if gvt >= LAST_CYCLE {
+                break;
+            }
+        }
+
+        let counts = handles.into_iter().map(|handle| handle.join().unwrap());
+        counts.flatten().collect()
+    }
+
+    fn merged() -> HashMap<usize, (usize, String)> {
+        let mut engine = Engine::new(Lefs::merge(subnets()).unwrap(), QueueKind::default());
+        let report = engine.simulate(engine.cycle, LAST_CYCLE);
+        outcome(&engine, &report)
+    }
+
+    #[test]
+    fn conservative_matches_merged() {
+        assert_eq!(distributed(SyncMode::Conservative), merged());
+    }
+
+    #[test]
+    fn optimistic_matches_merged() {
+        assert_eq!(distributed(SyncMode::Optimistic), merged());
+    }
+}
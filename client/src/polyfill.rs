@@ -1,58 +1,358 @@
-use crate::{engine::Event, error::Result};
-use std::{fmt::Display, fs::File, io::BufReader};
+use crate::{
+    engine::{Event, ExternalEvent},
+    error::{AppError, Result},
+    json,
+    validate::{validate, Diagnostic, Problem, Severity},
+};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{BufReader, Read},
+};
+use tracing::warn;
 
 #[derive(Debug)]
 pub struct Lefs {
     pub transitions: Vec<Transition>,
     pub estimulated_transition_indices: Vec<usize>,
+    // ii_idglobal -> position in transitions
+    pub indices: HashMap<usize, usize>,
+    // il_grupos_conflicto, positions in transitions listed by priority
+    pub conflict_groups: Vec<Vec<usize>>,
+    // il_eventos, il_eventosinmediatos and il_eventos_exteriores, the
+    // engine takes them over
+    pub events: Vec<Event>,
+    pub immediate_events: Vec<Event>,
+    pub external_events: Vec<ExternalEvent>,
 }
 
 impl Lefs {
+    // loads the model file at path
     pub fn new(path: &str) -> Result<Self> {
-        let file = File::open(path)?;
-        let rdr = BufReader::new(file);
-        let lefs: crate::json::Lefs = serde_json::from_reader(rdr)?;
+        let lefs = File::open(path)
+            .map_err(AppError::from)
+            .and_then(|file| Self::from_reader(BufReader::new(file)));
+        lefs.map_err(|error| AppError::File {
+            path: path.to_string(),
+            error: Box::new(error),
+        })
+    }
 
-        let transitions = lefs
-            .ia_red
+    pub fn from_reader(rdr: impl Read) -> Result<Self> {
+        let lefs: json::Lefs = serde_json::from_reader(rdr)?;
+        Self::from_json(lefs)
+    }
+
+    pub fn from_json(lefs: json::Lefs) -> Result<Self> {
+        let (errors, warnings): (Vec<_>, Vec<_>) = validate(&lefs)
             .into_iter()
-            .map(|transition| {
-                let iul_payloads = transition
-                    .ii_listactes_iul
-                    .iter()
-                    .map(|i| Payload {
-                        transition_index: i.0,
-                        constant: i.1,
-                    })
-                    .collect();
+            .partition(|diagnostic| diagnostic.severity == Severity::Error);
+        if !errors.is_empty() {
+            return Err(AppError::InvalidModel(errors));
+        }
+        for diagnostic in warnings {
+            warn!("{diagnostic}");
+        }
 
-                let pul_payloads = transition
-                    .ii_listactes_pul
-                    .iter()
-                    .map(|i| Payload {
-                        transition_index: i.0,
+        // payloads reference transitions by ii_idglobal, which only matches
+        // the position in ia_red when the net hasn't been partitioned
+        let indices: HashMap<usize, usize> = lefs
+            .ia_red
+            .iter()
+            .enumerate()
+            .map(|(i, transition)| (transition.ii_idglobal, i))
+            .collect();
+
+        let local_payload = |i: &json::Payload| {
+            usize::try_from(i.0)
+                .ok()
+                .and_then(|id| indices.get(&id))
+                .map(|&transition_index| Payload {
+                    transition_index,
+                    constant: i.1,
+                })
+                .ok_or(AppError::UnknownTransition(i.0))
+        };
+
+        let mut transitions = vec![];
+        for transition in lefs.ia_red {
+            let iul_payloads = transition
+                .ii_listactes_iul
+                .iter()
+                .map(local_payload)
+                .collect::<Result<_>>()?;
+
+            // a negative index -n in PUL stands for transition n - 1 of
+            // another subnet
+            let mut pul_payloads = vec![];
+            let mut external_payloads = vec![];
+            for i in &transition.ii_listactes_pul {
+                if i.0 < 0 {
+                    // isize::MIN stands for nothing, there's no n to take
+                    let n = i.0.checked_neg().ok_or(AppError::UnknownTransition(i.0))?;
+                    external_payloads.push(ExternalPayload {
+                        transition_id: (n - 1) as usize,
                         constant: i.1,
-                    })
-                    .collect();
-
-                Transition {
-                    id: transition.ii_idglobal,
-                    constant: transition.ii_valor,
-                    cycle: transition.ii_tiempo,
-                    duration: transition.ii_duracion_disparo,
-                    iul_payloads,
-                    pul_payloads,
+                    });
+                } else {
+                    pul_payloads.push(local_payload(i)?);
                 }
+            }
+
+            transitions.push(Transition {
+                id: transition.ii_idglobal,
+                constant: transition.ii_valor,
+                cycle: transition.ii_tiempo,
+                duration: transition.ii_duracion_disparo,
+                conflict_group: transition.ii_grupoconflicto,
+                fire_count: transition.ii_vecesdisparada,
+                output: transition.ib_desalida,
+                iul_payloads,
+                pul_payloads,
+                external_payloads,
+                lookaheads: transition
+                    .il_tiemposhastamarca
+                    .map_or(vec![], |tiempos| tiempos.il_tiempos),
+            });
+        }
+
+        let conflict_groups = lefs
+            .il_grupos_conflicto
+            .il_milista
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|id| {
+                        indices
+                            .get(&id)
+                            .copied()
+                            .ok_or(AppError::UnknownTransition(id as isize))
+                    })
+                    .collect::<Result<_>>()
+            })
+            .collect::<Result<_>>()?;
+
+        // events already scheduled when the file was written
+        let event = |event: json::Event| {
+            indices
+                .get(&event.ii_transicion)
+                .map(|&transition_index| Event {
+                    cycle: event.ii_tiempo,
+                    transition_index,
+                    constant: event.ii_cte,
+                })
+                .ok_or(AppError::UnknownTransition(event.ii_transicion as isize))
+        };
+        let events = lefs
+            .il_eventos
+            .il_milista
+            .into_iter()
+            .map(event)
+            .collect::<Result<_>>()?;
+        let immediate_events = lefs
+            .il_eventosinmediatos
+            .il_milista
+            .into_iter()
+            .map(event)
+            .collect::<Result<_>>()?;
+        let external_events = lefs
+            .il_eventos_exteriores
+            .il_milista
+            .into_iter()
+            .map(|event| ExternalEvent {
+                cycle: event.ii_tiempo,
+                transition_id: event.ii_transicion,
+                constant: event.ii_cte,
             })
             .collect();
 
         let lefs = Self {
             transitions,
             estimulated_transition_indices: vec![],
+            indices,
+            conflict_groups,
+            events,
+            immediate_events,
+            external_events,
         };
 
         Ok(lefs)
     }
+
+    // puts the subnets of a partitioned net back together, events they sent
+    // each other become local ones
+    pub fn merge(subnets: Vec<Lefs>) -> Result<Self> {
+        let mut merged = Lefs {
+            transitions: vec![],
+            estimulated_transition_indices: vec![],
+            indices: HashMap::new(),
+            conflict_groups: vec![],
+            events: vec![],
+            immediate_events: vec![],
+            external_events: vec![],
+        };
+
+        let mut external_events = vec![];
+        for (n, subnet) in subnets.into_iter().enumerate() {
+            let offset = merged.transitions.len();
+            let group_offset = merged.conflict_groups.len();
+            let local = |event: Event| Event {
+                transition_index: event.transition_index + offset,
+                ..event
+            };
+
+            for (i, mut transition) in subnet.transitions.into_iter().enumerate() {
+                if let Some(&first) = merged.indices.get(&transition.id) {
+                    let diagnostic = Diagnostic {
+                        severity: Severity::Error,
+                        path: format!("{n}:ia_red[{i}].ii_idglobal"),
+                        problem: Problem::DuplicateId {
+                            id: transition.id,
+                            first,
+                        },
+                    };
+                    return Err(AppError::InvalidModel(vec![diagnostic]));
+                }
+                merged.indices.insert(transition.id, offset + i);
+
+                for payload in transition
+                    .iul_payloads
+                    .iter_mut()
+                    .chain(transition.pul_payloads.iter_mut())
+                {
+                    payload.transition_index += offset;
+                }
+                transition.conflict_group =
+                    transition.conflict_group.map(|group| group + group_offset);
+                merged.transitions.push(transition);
+            }
+
+            merged.conflict_groups.extend(
+                subnet
+                    .conflict_groups
+                    .into_iter()
+                    .map(|group| group.into_iter().map(|i| i + offset).collect()),
+            );
+            merged.events.extend(subnet.events.into_iter().map(local));
+            merged
+                .immediate_events
+                .extend(subnet.immediate_events.into_iter().map(local));
+            external_events.extend(subnet.external_events);
+        }
+
+        // only transitions of subnets left out stay external
+        for transition in &mut merged.transitions {
+            let (local, external): (Vec<_>, Vec<_>) =
+                std::mem::take(&mut transition.external_payloads)
+                    .into_iter()
+                    .partition(|payload| merged.indices.contains_key(&payload.transition_id));
+            transition
+                .pul_payloads
+                .extend(local.into_iter().map(|payload| Payload {
+                    transition_index: merged.indices[&payload.transition_id],
+                    constant: payload.constant,
+                }));
+            transition.external_payloads = external;
+        }
+        for event in external_events {
+            match merged.indices.get(&event.transition_id) {
+                Some(&transition_index) => merged.events.push(Event {
+                    cycle: event.cycle,
+                    transition_index,
+                    constant: event.constant,
+                }),
+                None => merged.external_events.push(event),
+            }
+        }
+
+        Ok(merged)
+    }
+
+    // the model file the Go tool reads, with events as pending when saved
+    pub fn to_json<'a>(
+        &self,
+        events: impl IntoIterator<Item = &'a Event>,
+        external_events: impl IntoIterator<Item = &'a ExternalEvent>,
+    ) -> json::Lefs {
+        let id = |transition_index: usize| self.transitions[transition_index].id;
+        let payload = |payload: &Payload| {
+            json::Payload(id(payload.transition_index) as isize, payload.constant)
+        };
+
+        let ia_red = self
+            .transitions
+            .iter()
+            .map(|transition| json::Transition {
+                ii_idglobal: transition.id,
+                ii_valor: transition.constant,
+                ii_tiempo: transition.cycle,
+                ii_duracion_disparo: transition.duration,
+                ii_listactes_iul: transition.iul_payloads.iter().map(payload).collect(),
+                ii_listactes_pul: transition
+                    .pul_payloads
+                    .iter()
+                    .map(payload)
+                    .chain(transition.external_payloads.iter().map(|payload| {
+                        json::Payload(-(payload.transition_id as isize) - 1, payload.constant)
+                    }))
+                    .collect(),
+                ii_vecesdisparada: transition.fire_count,
+                ii_grupoconflicto: transition.conflict_group,
+                il_tiemposhastamarca: (!transition.lookaheads.is_empty()).then(|| json::Tiempos {
+                    il_tiempos: transition.lookaheads.clone(),
+                }),
+                ib_desalida: transition.output,
+            })
+            .collect();
+
+        // the Go tool expects event lists in time order
+        let mut il_eventos: Vec<json::Event> = events
+            .into_iter()
+            .map(|event| json::Event {
+                ii_tiempo: event.cycle,
+                ii_transicion: id(event.transition_index),
+                ii_cte: event.constant,
+            })
+            .collect();
+        il_eventos.sort_by_key(|event| event.ii_tiempo);
+        let mut il_eventos_exteriores: Vec<json::Event> = external_events
+            .into_iter()
+            .map(|event| json::Event {
+                ii_tiempo: event.cycle,
+                ii_transicion: event.transition_id,
+                ii_cte: event.constant,
+            })
+            .collect();
+        il_eventos_exteriores.sort_by_key(|event| event.ii_tiempo);
+
+        json::Lefs {
+            interior: json::INTERIOR,
+            exterior: json::EXTERIOR,
+            ia_red,
+            ii_indice: self.transitions.len(),
+            is_transicionessensibilizadas: self
+                .estimulated_transition_indices
+                .iter()
+                .map(|&i| id(i))
+                .collect(),
+            il_eventos: json::Lista {
+                il_milista: il_eventos,
+            },
+            il_eventosinmediatos: json::Lista::default(),
+            il_eventos_exteriores: json::Lista {
+                il_milista: il_eventos_exteriores,
+            },
+            il_grupos_conflicto: json::Lista {
+                il_milista: self
+                    .conflict_groups
+                    .iter()
+                    .map(|group| group.iter().map(|&i| id(i)).collect())
+                    .collect(),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -61,9 +361,30 @@ pub struct Transition {
     pub constant: isize,
     pub cycle: usize,
     pub duration: usize,
+    // ii_grupoconflicto, position in il_grupos_conflicto
+    pub conflict_group: Option<usize>,
+    // ii_vecesdisparada, carried over from the model file
+    pub fire_count: usize,
+    // ib_desalida
+    pub output: bool,
     // I don't know what iul and pul mean, i stands for immediate
     pub iul_payloads: Vec<Payload>,
     pub pul_payloads: Vec<Payload>,
+    // PUL payloads aimed at transitions of other subnets
+    pub external_payloads: Vec<ExternalPayload>,
+    // iL_tiemposhastamarca
+    pub lookaheads: Vec<usize>,
+}
+
+impl Transition {
+    // no event produced by firing this transition can land sooner than
+    // this, il_tiempos may only tighten the firing duration
+    pub fn lookahead(&self) -> usize {
+        self.lookaheads
+            .iter()
+            .copied()
+            .fold(self.duration, usize::min)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -72,6 +393,12 @@ pub struct Payload {
     pub constant: isize,
 }
 
+#[derive(Debug, Clone)]
+pub struct ExternalPayload {
+    pub transition_id: usize,
+    pub constant: isize,
+}
+
 impl Display for Lefs {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "STRUCT LEFS")?;
@@ -108,6 +435,14 @@ impl Display for Transition {
                 payload.transition_index, payload.constant
             )?;
         }
+        for payload in &self.external_payloads {
+            writeln!(
+                f,
+                "\tTRANSICION: {}\t\tCTE: {}",
+                -(payload.transition_id as isize) - 1,
+                payload.constant
+            )?;
+        }
         Ok(())
     }
 }
@@ -119,3 +454,11 @@ impl Display for Event {
         writeln!(f, "    CONSTANTE: {}", self.constant)
     }
 }
+
+impl Display for ExternalEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "    TIEMPO: {}", self.cycle)?;
+        writeln!(f, "    TRANSICION EXTERIOR: {}", self.transition_id)?;
+        writeln!(f, "    CONSTANTE: {}", self.constant)
+    }
+}
@@ -0,0 +1,156 @@
+use crate::engine::Engine;
+use chrono::prelude::*;
+use serde::Serialize;
+use std::fmt::Display;
+
+// What a run of the engine amounted to, for the net as a whole and for each
+// of its transitions
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulationReport {
+    pub first_cycle: usize,
+    pub last_cycle: usize,
+    pub event_count: usize,
+    pub fire_count: usize,
+    pub elapsed_microseconds: i64,
+    pub events_per_second: f64,
+    // firings of every ib_desalida transition per cycle
+    pub throughput: f64,
+    pub transitions: Vec<TransitionReport>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TransitionReport {
+    // ii_idglobal
+    pub id: usize,
+    pub output: bool,
+    // firings during the run, not counting ii_vecesdisparada
+    pub fire_count: usize,
+    pub first_firing: Option<usize>,
+    pub last_firing: Option<usize>,
+    // cycles between two firings, on average
+    pub mean_interval: Option<f64>,
+    // share of the run spent firing, above 1 when firings overlap
+    pub utilisation: f64,
+    // firings per cycle, for output transitions only
+    pub throughput: Option<f64>,
+}
+
+impl SimulationReport {
+    // the run went from first_cycle to last_cycle and started at start
+    pub fn new(
+        engine: &Engine,
+        start: DateTime<Utc>,
+        first_cycle: usize,
+        last_cycle: usize,
+    ) -> Self {
+        let elapsed = (Utc::now() - start).num_microseconds().unwrap_or(i64::MAX);
+        let horizon = last_cycle.saturating_sub(first_cycle);
+        let per_cycle = |count: usize| count as f64 / horizon.max(1) as f64;
+
+        let mut firings = vec![vec![]; engine.lefs.transitions.len()];
+        for log in &engine.logs {
+            firings[log.estimulated_transition_index].push(log.cycle);
+        }
+
+        let transitions: Vec<TransitionReport> = engine
+            .lefs
+            .transitions
+            .iter()
+            .zip(&firings)
+            .enumerate()
+            .map(|(i, (transition, cycles))| {
+                // firings fossil collection already totalled come first
+                let fossils = engine.fossils.firings.get(i).copied().unwrap_or_default();
+                let fire_count = fossils.count + cycles.len();
+                let first_firing = fossils.first.or(cycles.first().copied());
+                let last_firing = cycles.last().copied().or(fossils.last);
+                let mean_interval = match (first_firing, last_firing) {
+                    (Some(first), Some(last)) if fire_count > 1 => {
+                        Some((last - first) as f64 / (fire_count - 1) as f64)
+                    }
+                    _ => None,
+                };
+                // firings still going at the end of the run count up to it
+                let busy: usize = cycles
+                    .iter()
+                    .map(|&cycle| {
+                        (cycle + transition.duration).min(last_cycle) - cycle.min(last_cycle)
+                    })
+                    .sum();
+
+                TransitionReport {
+                    id: transition.id,
+                    output: transition.output,
+                    fire_count,
+                    first_firing,
+                    last_firing,
+                    mean_interval,
+                    utilisation: per_cycle(fossils.busy + busy),
+                    throughput: transition.output.then(|| per_cycle(fire_count)),
+                }
+            })
+            .collect();
+
+        let output_count = transitions
+            .iter()
+            .filter(|transition| transition.output)
+            .map(|transition| transition.fire_count)
+            .sum();
+
+        SimulationReport {
+            first_cycle,
+            last_cycle,
+            event_count: engine.event_count,
+            fire_count: engine.fossils.log_count + engine.logs.len(),
+            elapsed_microseconds: elapsed,
+            events_per_second: engine.event_count as f64 * 1e6 / elapsed.max(1) as f64,
+            throughput: per_cycle(output_count),
+            transitions,
+        }
+    }
+}
+
+impl Display for SimulationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+
+        writeln!(f, "cycles: {} -> {}", self.first_cycle, self.last_cycle)?;
+        writeln!(f, "event_count: {}", self.event_count)?;
+        writeln!(f, "elapsed: {} microseconds", self.elapsed_microseconds)?;
+        writeln!(f, "events per second: {:.0}", self.events_per_second)?;
+        writeln!(f, "fire_count: {}", self.fire_count)?;
+        writeln!(f, "throughput: {:.4} per cycle", self.throughput)?;
+        write!(
+            f,
+            "{:>10} {:>6} {:>8} {:>8} {:>8} {:>10} {:>11} {:>10}",
+            "transition",
+            "output",
+            "fired",
+            "first",
+            "last",
+            "interval",
+            "utilisation",
+            "throughput"
+        )?;
+        for transition in &self.transitions {
+            write!(
+                f,
+                "\n{:>10} {:>6} {:>8} {:>8} {:>8} {:>10} {:>11.4} {:>10}",
+                transition.id,
+                if transition.output { "yes" } else { "" },
+                transition.fire_count,
+                optional(transition.first_firing.map(|cycle| cycle.to_string())),
+                optional(transition.last_firing.map(|cycle| cycle.to_string())),
+                optional(transition.mean_interval.map(|mean| format!("{mean:.2}"))),
+                transition.utilisation,
+                optional(
+                    transition
+                        .throughput
+                        .map(|throughput| format!("{throughput:.4}"))
+                ),
+            )?;
+        }
+
+        Ok(())
+    }
+}
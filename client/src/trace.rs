@@ -0,0 +1,178 @@
+use crate::error::{AppError, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TraceFormat {
+    Csv,
+    JsonLines,
+}
+
+impl FromStr for TraceFormat {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(TraceFormat::Csv),
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            _ => Err(AppError::UnknownValue {
+                setting: "trace format",
+                value: s.to_string(),
+                expected: "csv or jsonl",
+            }),
+        }
+    }
+}
+
+impl Display for TraceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceFormat::Csv => write!(f, "csv"),
+            TraceFormat::JsonLines => write!(f, "jsonl"),
+        }
+    }
+}
+
+// what happened to a transition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceKind {
+    // the transition fired, value is its constant when it did
+    Fired,
+    // an event was applied to the transition
+    Event,
+    // an IUL payload changed the constant of the transition
+    Constant,
+    // the engine went back to cycle, records after it no longer hold
+    Rollback,
+}
+
+impl Display for TraceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceKind::Fired => write!(f, "fired"),
+            TraceKind::Event => write!(f, "event"),
+            TraceKind::Constant => write!(f, "constant"),
+            TraceKind::Rollback => write!(f, "rollback"),
+        }
+    }
+}
+
+// one row of the trace, transition is the ii_idglobal
+#[derive(Debug, Clone, Serialize)]
+pub struct Record<'a> {
+    pub subnet: &'a str,
+    pub cycle: usize,
+    pub kind: TraceKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transition: Option<usize>,
+    // change applied to the constant
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<isize>,
+    // the constant afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<isize>,
+}
+
+// Streams what the engine does to a CSV or JSON Lines file as it goes, one
+// record per line
+pub struct TraceWriter {
+    format: TraceFormat,
+    subnet: String,
+    out: Box<dyn Write + Send>,
+    // the first write that failed, reported by finish
+    error: Option<io::Error>,
+}
+
+impl std::fmt::Debug for TraceWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceWriter")
+            .field("format", &self.format)
+            .field("subnet", &self.subnet)
+            .finish()
+    }
+}
+
+impl TraceWriter {
+    pub fn new(format: TraceFormat, subnet: &str, out: Box<dyn Write + Send>) -> Result<Self> {
+        let mut writer = TraceWriter {
+            format,
+            subnet: subnet.to_string(),
+            out,
+            error: None,
+        };
+        if format == TraceFormat::Csv {
+            writeln!(writer.out, "subnet,cycle,kind,transition,delta,value")?;
+        }
+        Ok(writer)
+    }
+
+    pub fn create(format: TraceFormat, subnet: &str, path: &str) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::new(format, subnet, Box::new(BufWriter::new(file)))
+    }
+
+    pub fn record(
+        &mut self,
+        cycle: usize,
+        kind: TraceKind,
+        transition: Option<usize>,
+        delta: Option<isize>,
+        value: Option<isize>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let record = Record {
+            subnet: &self.subnet,
+            cycle,
+            kind,
+            transition,
+            delta,
+            value,
+        };
+        let written = match self.format {
+            TraceFormat::Csv => write_csv(&mut self.out, &record),
+            TraceFormat::JsonLines => serde_json::to_writer(&mut self.out, &record)
+                .map_err(io::Error::from)
+                .and_then(|_| writeln!(self.out)),
+        };
+        self.error = written.err();
+    }
+
+    // flushes the trace and reports the first write that failed, if any
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error.into());
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn write_csv(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let optional = |value: Option<isize>| value.map_or(String::new(), |value| value.to_string());
+
+    // subnets are named after model files, which may hold commas or quotes
+    let subnet = if record.subnet.contains([',', '"', '\n']) {
+        format!("\"{}\"", record.subnet.replace('"', "\"\""))
+    } else {
+        record.subnet.to_string()
+    };
+    writeln!(
+        out,
+        "{},{},{},{},{},{}",
+        subnet,
+        record.cycle,
+        record.kind,
+        record.transition.map_or(String::new(), |id| id.to_string()),
+        optional(record.delta),
+        optional(record.value)
+    )
+}
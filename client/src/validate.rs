@@ -0,0 +1,448 @@
+use crate::json;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    // the model loads, but is likely not what was meant
+    Warning,
+    // the engine can't simulate the model
+    Error,
+}
+
+// something wrong with a model, path points at the offending JSON value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub problem: Problem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    // a payload, event or conflict group names an ii_idglobal not in ia_red
+    UnknownTransition {
+        id: isize,
+    },
+    // IUL payloads act within the subnet, they can't be external
+    ExternalIul {
+        id: isize,
+    },
+    DuplicateId {
+        id: usize,
+        first: usize,
+    },
+    // ids missing between the lowest and the highest ii_idglobal
+    IdGap {
+        from: usize,
+        to: usize,
+    },
+    IndexMismatch {
+        ii_indice: usize,
+        transitions: usize,
+    },
+    // ii_grupoconflicto past the end of il_grupos_conflicto
+    UnknownConflictGroup {
+        group: usize,
+    },
+    // the transition isn't listed in its own conflict group
+    NotInConflictGroup {
+        id: usize,
+        group: usize,
+    },
+    // transitions of zero duration feeding each other may never let the
+    // clock move, ids are in firing order
+    ZeroDurationLoop {
+        ids: Vec<usize>,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::UnknownTransition { id } => write!(f, "unknown transition {id}"),
+            Problem::ExternalIul { id } => {
+                write!(f, "IUL payload {id} references another subnet")
+            }
+            Problem::DuplicateId { id, first } => {
+                write!(f, "ii_idglobal {id} already used by ia_red[{first}]")
+            }
+            Problem::IdGap { from, to } => write!(f, "no transition with ids {from} to {to}"),
+            Problem::IndexMismatch {
+                ii_indice,
+                transitions,
+            } => write!(
+                f,
+                "ii_indice is {ii_indice} but ia_red holds {transitions} transitions"
+            ),
+            Problem::UnknownConflictGroup { group } => {
+                write!(f, "unknown conflict group {group}")
+            }
+            Problem::NotInConflictGroup { id, group } => {
+                write!(f, "transition {id} missing from conflict group {group}")
+            }
+            Problem::ZeroDurationLoop { ids } => {
+                write!(f, "zero duration loop through transitions {ids:?}")
+            }
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.problem)
+    }
+}
+
+// every problem found in the model, errors first
+pub fn validate(lefs: &json::Lefs) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut report = |severity, path: String, problem| {
+        diagnostics.push(Diagnostic {
+            severity,
+            path,
+            problem,
+        })
+    };
+
+    if lefs.ii_indice != lefs.ia_red.len() {
+        let problem = Problem::IndexMismatch {
+            ii_indice: lefs.ii_indice,
+            transitions: lefs.ia_red.len(),
+        };
+        report(Severity::Warning, "ii_indice".to_string(), problem);
+    }
+
+    let mut indices: HashMap<usize, usize> = HashMap::new();
+    for (i, transition) in lefs.ia_red.iter().enumerate() {
+        let id = transition.ii_idglobal;
+        if let Some(&first) = indices.get(&id) {
+            let path = format!("ia_red[{i}].ii_idglobal");
+            report(Severity::Error, path, Problem::DuplicateId { id, first });
+        } else {
+            indices.insert(id, i);
+        }
+    }
+
+    // subnets hold a range of the whole net's ids
+    let mut ids: Vec<usize> = indices.keys().copied().collect();
+    ids.sort();
+    for pair in ids.windows(2) {
+        if pair[1] > pair[0] + 1 {
+            let problem = Problem::IdGap {
+                from: pair[0] + 1,
+                to: pair[1] - 1,
+            };
+            report(Severity::Warning, "ia_red".to_string(), problem);
+        }
+    }
+
+    let known = |id: isize| usize::try_from(id).is_ok_and(|id| indices.contains_key(&id));
+    let groups = &lefs.il_grupos_conflicto.il_milista;
+    for (i, transition) in lefs.ia_red.iter().enumerate() {
+        for (j, payload) in transition.ii_listactes_iul.iter().enumerate() {
+            let path = format!("ia_red[{i}].ii_listactes_IUL[{j}]");
+            if payload.0 < 0 {
+                report(
+                    Severity::Error,
+                    path,
+                    Problem::ExternalIul { id: payload.0 },
+                );
+            } else if !known(payload.0) {
+                let problem = Problem::UnknownTransition { id: payload.0 };
+                report(Severity::Error, path, problem);
+            }
+        }
+
+        // negative entries belong to other subnets, nothing to check here
+        // but that they name a transition at all
+        for (j, payload) in transition.ii_listactes_pul.iter().enumerate() {
+            let unknown = match payload.0.checked_neg() {
+                Some(_) => payload.0 >= 0 && !known(payload.0),
+                None => true,
+            };
+            if unknown {
+                let path = format!("ia_red[{i}].ii_listactes_PUL[{j}]");
+                let problem = Problem::UnknownTransition { id: payload.0 };
+                report(Severity::Error, path, problem);
+            }
+        }
+
+        // without il_grupos_conflicto groups are just labels
+        let Some(group) = transition.ii_grupoconflicto else {
+            continue;
+        };
+        let path = format!("ia_red[{i}].ii_grupoconflicto");
+        match groups.get(group) {
+            None if !groups.is_empty() => {
+                report(
+                    Severity::Warning,
+                    path,
+                    Problem::UnknownConflictGroup { group },
+                );
+            }
+            Some(ids) if !ids.contains(&transition.ii_idglobal) => {
+                let id = transition.ii_idglobal;
+                report(
+                    Severity::Warning,
+                    path,
+                    Problem::NotInConflictGroup { id, group },
+                );
+            }
+            _ => {}
+        }
+    }
+
+    for (i, group) in groups.iter().enumerate() {
+        for (j, &id) in group.iter().enumerate() {
+            if !indices.contains_key(&id) {
+                let path = format!("il_grupos_conflicto.il_milista[{i}][{j}]");
+                let problem = Problem::UnknownTransition { id: id as isize };
+                report(Severity::Error, path, problem);
+            }
+        }
+    }
+
+    for (name, events) in [
+        ("il_eventos", &lefs.il_eventos),
+        ("il_eventosinmediatos", &lefs.il_eventosinmediatos),
+    ] {
+        for (i, event) in events.il_milista.iter().enumerate() {
+            if !indices.contains_key(&event.ii_transicion) {
+                let path = format!("{name}.il_milista[{i}].ii_transicion");
+                let problem = Problem::UnknownTransition {
+                    id: event.ii_transicion as isize,
+                };
+                report(Severity::Error, path, problem);
+            }
+        }
+    }
+
+    for (i, &id) in lefs.is_transicionessensibilizadas.iter().enumerate() {
+        if !indices.contains_key(&id) {
+            let path = format!("is_transicionessensibilizadas[{i}]");
+            let problem = Problem::UnknownTransition { id: id as isize };
+            report(Severity::Warning, path, problem);
+        }
+    }
+
+    for ids in zero_duration_loops(lefs, &indices) {
+        let path = format!("ia_red[{}].ii_listactes_PUL", indices[&ids[0]]);
+        report(Severity::Warning, path, Problem::ZeroDurationLoop { ids });
+    }
+
+    diagnostics.sort_by_key(|diagnostic| std::cmp::Reverse(diagnostic.severity));
+    diagnostics
+}
+
+// loops of PUL payloads among zero duration transitions
+fn zero_duration_loops(lefs: &json::Lefs, indices: &HashMap<usize, usize>) -> Vec<Vec<usize>> {
+    let instant = |i: usize| lefs.ia_red[i].ii_duracion_disparo == 0;
+    let successors = |i: usize| {
+        lefs.ia_red[i]
+            .ii_listactes_pul
+            .iter()
+            .filter_map(|payload| usize::try_from(payload.0).ok())
+            .filter_map(|id| indices.get(&id).copied())
+            .filter(move |&j| instant(j))
+    };
+
+    // depth first, a successor still on the path closes a loop
+    let mut loops = vec![];
+    let mut done = HashSet::new();
+    for root in (0..lefs.ia_red.len()).filter(|&i| instant(i)) {
+        if done.contains(&root) {
+            continue;
+        }
+
+        let mut path = vec![root];
+        let mut pending = vec![successors(root).collect::<Vec<_>>()];
+        while let Some(next) = pending.last_mut() {
+            let Some(j) = next.pop() else {
+                done.insert(path.pop().unwrap());
+                pending.pop();
+                continue;
+            };
+
+            if let Some(start) = path.iter().position(|&k| k == j) {
+                let ids = path[start..].iter().map(|&k| lefs.ia_red[k].ii_idglobal);
+                loops.push(ids.collect());
+            } else if !done.contains(&j) {
+                path.push(j);
+                pending.push(successors(j).collect());
+            }
+        }
+    }
+
+    loops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{Event, Lista, Payload};
+
+    // transitions 0..count of one cycle each, feeding nobody
+    fn net(count: usize) -> json::Lefs {
+        let ia_red = (0..count)
+            .map(|id| json::Transition {
+                ii_idglobal: id,
+                ii_valor: 0,
+                ii_tiempo: 0,
+                ii_duracion_disparo: 1,
+                ii_listactes_iul: vec![],
+                ii_listactes_pul: vec![],
+                ii_vecesdisparada: 0,
+                ii_grupoconflicto: None,
+                il_tiemposhastamarca: None,
+                ib_desalida: false,
+            })
+            .collect();
+
+        json::Lefs {
+            interior: json::INTERIOR,
+            exterior: json::EXTERIOR,
+            ia_red,
+            ii_indice: count,
+            is_transicionessensibilizadas: vec![],
+            il_eventos: Lista::default(),
+            il_eventosinmediatos: Lista::default(),
+            il_eventos_exteriores: Lista::default(),
+            il_grupos_conflicto: Lista::default(),
+        }
+    }
+
+    // as lefs validate prints them
+    fn diagnostics(lefs: &json::Lefs) -> Vec<String> {
+        let diagnostics = validate(lefs).into_iter();
+        diagnostics
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sound_net() {
+        let mut lefs = net(2);
+        lefs.ia_red[0].ii_listactes_pul = vec![Payload(1, 1), Payload(-5, 1)];
+        lefs.ia_red[1].ii_listactes_iul = vec![Payload(1, -1)];
+        lefs.ia_red[0].ii_grupoconflicto = Some(0);
+        lefs.ia_red[1].ii_grupoconflicto = Some(0);
+        lefs.il_grupos_conflicto.il_milista = vec![vec![0, 1]];
+        assert_eq!(diagnostics(&lefs), Vec::<String>::new());
+
+        for i in 0..3 {
+            let path = format!("{}/3subredes.subred{i}.json", env!("CARGO_MANIFEST_DIR"));
+            let lefs = serde_json::from_reader(std::fs::File::open(path).unwrap()).unwrap();
+            assert_eq!(diagnostics(&lefs), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn unknown_transition() {
+        let mut lefs = net(1);
+        lefs.ia_red[0].ii_listactes_iul = vec![Payload(7, 1)];
+        lefs.ia_red[0].ii_listactes_pul = vec![Payload(8, 1), Payload(isize::MIN, 1)];
+        lefs.il_grupos_conflicto.il_milista = vec![vec![9]];
+        lefs.il_eventos.il_milista = vec![Event {
+            ii_tiempo: 0,
+            ii_transicion: 10,
+            ii_cte: 1,
+        }];
+        lefs.is_transicionessensibilizadas = vec![11];
+        assert_eq!(
+            diagnostics(&lefs),
+            [
+                "error: ia_red[0].ii_listactes_IUL[0]: unknown transition 7",
+                "error: ia_red[0].ii_listactes_PUL[0]: unknown transition 8",
+                "error: ia_red[0].ii_listactes_PUL[1]: unknown transition -9223372036854775808",
+                "error: il_grupos_conflicto.il_milista[0][0]: unknown transition 9",
+                "error: il_eventos.il_milista[0].ii_transicion: unknown transition 10",
+                "warning: is_transicionessensibilizadas[0]: unknown transition 11",
+            ]
+        );
+    }
+
+    #[test]
+    fn external_iul() {
+        let mut lefs = net(1);
+        lefs.ia_red[0].ii_listactes_iul = vec![Payload(-1, 1)];
+        assert_eq!(
+            diagnostics(&lefs),
+            ["error: ia_red[0].ii_listactes_IUL[0]: IUL payload -1 references another subnet"]
+        );
+    }
+
+    #[test]
+    fn duplicate_id() {
+        let mut lefs = net(2);
+        lefs.ia_red[1].ii_idglobal = 0;
+        assert_eq!(
+            diagnostics(&lefs),
+            ["error: ia_red[1].ii_idglobal: ii_idglobal 0 already used by ia_red[0]"]
+        );
+    }
+
+    #[test]
+    fn id_gap() {
+        let mut lefs = net(2);
+        lefs.ia_red[1].ii_idglobal = 3;
+        assert_eq!(
+            diagnostics(&lefs),
+            ["warning: ia_red: no transition with ids 1 to 2"]
+        );
+    }
+
+    #[test]
+    fn index_mismatch() {
+        let mut lefs = net(2);
+        lefs.ii_indice = 5;
+        assert_eq!(
+            diagnostics(&lefs),
+            ["warning: ii_indice: ii_indice is 5 but ia_red holds 2 transitions"]
+        );
+    }
+
+    #[test]
+    fn unknown_conflict_group() {
+        let mut lefs = net(2);
+        lefs.ia_red[0].ii_grupoconflicto = Some(3);
+        lefs.il_grupos_conflicto.il_milista = vec![vec![1]];
+        assert_eq!(
+            diagnostics(&lefs),
+            ["warning: ia_red[0].ii_grupoconflicto: unknown conflict group 3"]
+        );
+    }
+
+    #[test]
+    fn not_in_conflict_group() {
+        let mut lefs = net(2);
+        lefs.ia_red[0].ii_grupoconflicto = Some(0);
+        lefs.il_grupos_conflicto.il_milista = vec![vec![1]];
+        assert_eq!(
+            diagnostics(&lefs),
+            ["warning: ia_red[0].ii_grupoconflicto: transition 0 missing from conflict group 0"]
+        );
+    }
+
+    #[test]
+    fn zero_duration_loop() {
+        let mut lefs = net(3);
+        for (i, next) in [(0, 1), (1, 2), (2, 1)] {
+            lefs.ia_red[i].ii_duracion_disparo = 0;
+            lefs.ia_red[i].ii_listactes_pul = vec![Payload(next, 1)];
+        }
+        assert_eq!(
+            diagnostics(&lefs),
+            ["warning: ia_red[1].ii_listactes_PUL: zero duration loop through transitions [1, 2]"]
+        );
+    }
+}
@@ -1,42 +1,375 @@
-use std::io::{prelude::*, BufReader};
-use std::net::{TcpListener, TcpStream};
+mod manifest;
+
+use lefs::message::{self, Message, NodeInfo};
+use lefs::Model;
+use manifest::Manifest;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::thread;
+use std::time::{Duration, Instant};
+
+// pause between two GVT rounds
+const GVT_INTERVAL: Duration = Duration::from_millis(100);
 
+// server <manifest> [--shutdown], see Manifest
+//   --shutdown  stop the clients once the run is over
 fn main() {
-    let handle = thread::spawn(|| {
-        // server listens to communication from clients
-        let listener = TcpListener::bind("127.0.0.1:8888").unwrap();
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().partition(|arg| arg.starts_with("--"));
+    let shutdown = flags.iter().any(|flag| flag == "--shutdown");
+    let Some(path) = args.get(1) else {
+        eprintln!("usage: server <manifest> [--shutdown]");
+        process::exit(2);
+    };
 
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
+    // nothing is sent until the whole manifest checks out
+    let manifest = Manifest::open(path).unwrap_or_else(|error| {
+        eprintln!("{path}: {error}");
+        process::exit(1);
+    });
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    let plan = manifest.plan(dir).unwrap_or_else(|problems| {
+        for problem in problems {
+            eprintln!("{path}: {problem}");
+        }
+        process::exit(1);
+    });
+    let address = manifest.address.clone();
+    let last_cycle = manifest.last_cycle;
+    let timeout = Duration::from_millis(manifest.node_timeout_ms);
 
-            handle_connection(stream);
+    let (sender, receiver) = mpsc::channel();
+    let listener = TcpListener::bind(&address).unwrap();
+    thread::spawn(move || {
+        // server listens to communication from clients, each one over its own
+        // connection
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    println!("{error}");
+                    continue;
+                }
+            };
+            let sender = sender.clone();
+            thread::spawn(move || {
+                if let Err(error) = message::receive(stream, &sender, 0) {
+                    println!("{error}");
+                }
+            });
         }
     });
 
-    // server sends work to clients
-    let instructions = [
-        (r"Ejemplo1ParaTests.rdp.subred0.json", "127.0.0.1:8000"),
-        (r"Ejemplo1ParaTests.rdp.subred0.json", "127.0.0.1:8001"),
-        (r"Ejemplo1ParaTests.rdp.subred0.json", "127.0.0.1:8002"),
-    ];
+    let mut run = Run {
+        running: HashSet::new(),
+        done: vec![],
+        receiver,
+        models: plan
+            .subnets
+            .iter()
+            .map(|(_, model, _)| (model.hash.clone(), model.clone()))
+            .collect(),
+        nodes: HashMap::new(),
+        jobs: HashMap::new(),
+        seen: HashMap::new(),
+        timeout,
+    };
 
-    for (path, address) in instructions {
-        let mut stream = TcpStream::connect(address).unwrap();
-        let message = format!("{}\n\n", path);
-        stream.write_all(message.as_bytes()).unwrap();
+    // clients find us rather than the other way round, the run starts once
+    // there's a node for every subnet
+    println!("waiting for nodes on {address}");
+    let jobs = loop {
+        if let Some(jobs) = plan.assign(&run.nodes) {
+            break jobs;
+        }
+        let notice = run.next();
+        run.handle(notice);
+    };
+
+    // server sends work to clients, which get the models from us rather
+    // than from their own disks
+    for (node, job) in jobs {
+        println!("{} simulates {}", node.id, job.path);
+        run.running.insert(node.address.clone());
+        run.seen.insert(node.address.clone(), Instant::now());
+        let address = node.address.clone();
+        run.jobs
+            .insert(address.clone(), (node, job.path.clone(), None));
+        run.send(&address, &Message::Job(job));
     }
+    let start = Instant::now();
+
+    // GVT rounds go on until no client has anything left to simulate before
+    // last_cycle and no event for those cycles is on its way
+    let mut round = 0;
+    let mut gvt = 0;
+    while !run.running.is_empty() {
+        round += 1;
+        let marker = Message::Marker {
+            address: address.clone(),
+            round,
+        };
+        for address in run.running.clone() {
+            run.send(&address, &marker);
+        }
+
+        let Some(reports) = run.reports(round) else {
+            break;
+        };
+        gvt = reports.into_values().min().unwrap();
 
-    handle.join().unwrap();
+        let message = Message::Gvt { round, cycle: gvt };
+        for address in run.running.clone() {
+            run.send(&address, &message);
+        }
+
+        if gvt >= last_cycle {
+            run.wait();
+            break;
+        }
+
+        thread::sleep(GVT_INTERVAL);
+    }
+
+    println!(
+        "run finished: gvt {gvt} after {round} rounds, {} ms",
+        start.elapsed().as_millis()
+    );
+    for (address, event_count, path) in &run.done {
+        println!("{address} processed petri network {path}: {event_count} events");
+    }
+
+    if shutdown {
+        for node in run.nodes.clone().into_values() {
+            run.send(&node.address, &Message::Shutdown);
+        }
+    }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let message: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
+struct Run {
+    running: HashSet<String>,
+    // address, event_count, path
+    done: Vec<(String, usize, String)>,
+    receiver: Receiver<Message>,
+    // hash -> model, for clients that don't have it yet
+    models: HashMap<String, Model>,
+    // id -> every node that registered
+    nodes: HashMap<String, NodeInfo>,
+    // address -> node, subnet it simulates and the last cycle it reported
+    jobs: HashMap<String, (NodeInfo, String, Option<usize>)>,
+    // address -> when we last heard from the node, running or not
+    seen: HashMap<String, Instant>,
+    // silence after which a running node is taken for dead
+    timeout: Duration,
+}
+
+impl Run {
+    // every running client's report for round, None once nobody's running
+    fn reports(&mut self, round: usize) -> Option<HashMap<String, usize>> {
+        let mut reports = HashMap::new();
+        while reports.len() < self.running.len() {
+            match self.next() {
+                Message::Report {
+                    address,
+                    round: reported,
+                    cycle,
+                } if reported == round => {
+                    reports.insert(address, cycle);
+                }
+                notice => {
+                    if let Some(address) = self.handle(notice) {
+                        reports.remove(&address);
+                    }
+                }
+            }
+        }
 
-    println!("{:?}", message);
+        (!self.running.is_empty()).then_some(reports)
+    }
+
+    fn wait(&mut self) {
+        while !self.running.is_empty() {
+            let notice = self.next();
+            self.handle(notice);
+        }
+    }
+
+    // the next notice from a client, making sure in the meantime that every
+    // running one is still alive
+    fn next(&mut self) -> Message {
+        loop {
+            let silent = self
+                .running
+                .iter()
+                .find(|address| self.seen[*address].elapsed() > self.timeout);
+            if let Some(address) = silent {
+                let reason = format!(
+                    "no word for {} ms",
+                    self.seen[address].elapsed().as_millis()
+                );
+                self.fail(address, &reason);
+            }
+
+            let notice = match self.receiver.recv_timeout(self.timeout / 4) {
+                Ok(notice) => notice,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => panic!("listener gone"),
+            };
+            if let Some(address) = sender(&notice) {
+                self.seen.insert(address.clone(), Instant::now());
+            }
+            if let Message::Report { address, cycle, .. } = &notice {
+                if let Some((_, _, last)) = self.jobs.get_mut(address) {
+                    *last = Some(*cycle);
+                }
+            }
+
+            return notice;
+        }
+    }
+
+    // whether we heard from the node at address lately
+    fn is_alive(&self, address: &str) -> bool {
+        let seen = self.seen.get(address);
+        seen.is_some_and(|seen| seen.elapsed() <= self.timeout)
+    }
+
+    // a running node that can't be reached dooms the run
+    fn send(&mut self, address: &str, message: &Message) {
+        if let Err(error) = message.send(address) {
+            if self.running.contains(address) {
+                self.fail(address, &error.to_string());
+            }
+            println!("{error}");
+        }
+    }
+
+    // reports the node at address as failed and calls the run off, unless
+    // it isn't running a job: there's no run of its to call off then
+    fn fail(&self, address: &str, reason: &str) {
+        let job = self.jobs.get(address);
+        let Some((node, path, cycle)) = job.filter(|_| self.running.contains(address)) else {
+            println!("{address} failed outside a run: {reason}");
+            return;
+        };
+        // nodes with nothing pending report usize::MAX
+        let progress = match cycle {
+            None => "no report yet".to_string(),
+            Some(usize::MAX) => "nothing pending at its last report".to_string(),
+            Some(cycle) => format!("last reported cycle {cycle}"),
+        };
+        println!(
+            "{} at {address} failed simulating {path}: {reason}, {progress}",
+            node.id
+        );
+        let abort = Message::Abort {
+            reason: format!("{} failed simulating {path}", node.id),
+        };
+        for peer in &self.running {
+            if peer != address {
+                if let Err(error) = abort.send(peer) {
+                    println!("{error}");
+                }
+            }
+        }
+        println!("run aborted");
+        process::exit(1);
+    }
+
+    // None unless notice says a client is done
+    fn handle(&mut self, notice: Message) -> Option<String> {
+        match notice {
+            Message::Done {
+                address,
+                event_count,
+                path,
+            } => {
+                self.running.remove(&address);
+                self.done.push((address.clone(), event_count, path));
+                Some(address)
+            }
+            Message::Register(node) => {
+                // two nodes alive under the same id would take turns
+                // replacing each other
+                let taken = self.nodes.get(&node.id).filter(|old| {
+                    old.address != node.address
+                        && !self.running.contains(&old.address)
+                        && self.is_alive(&old.address)
+                });
+                if let Some(old) = taken {
+                    let message = format!("id {} taken by the node at {}", node.id, old.address);
+                    println!("{} refused: {message}", node.address);
+                    self.send(&node.address, &Message::Error { message });
+                    return None;
+                }
+
+                match self.nodes.insert(node.id.clone(), node.clone()) {
+                    // a restart loses the job, the run can't go on without it
+                    Some(old) if self.running.contains(&old.address) => {
+                        let reason = format!("restarted at {}", node.address);
+                        self.fail(&old.address, &reason);
+                    }
+                    Some(_) => println!("{} registered again at {}", node.id, node.address),
+                    None => println!(
+                        "{} registered at {}: {} cores",
+                        node.id, node.address, node.cores
+                    ),
+                }
+                None
+            }
+            // a node registered with a coordinator that was here before us
+            Message::Heartbeat { address } => {
+                if !self.nodes.values().any(|node| node.address == address) {
+                    self.send(&address, &Message::RegisterAgain);
+                }
+                None
+            }
+            Message::JobFailed { address, error, .. } => {
+                self.fail(&address, &error);
+                None
+            }
+            Message::JobAccepted { address, path } => {
+                println!("{address} started on {path}");
+                None
+            }
+            Message::Error { message } => {
+                println!("{message}");
+                None
+            }
+            Message::Need { address, hash } => {
+                match self.models.get(&hash) {
+                    Some(model) => {
+                        let (encoding, data) = model.encode().unwrap();
+                        let message = Message::Model {
+                            hash,
+                            encoding,
+                            data,
+                        };
+                        self.send(&address, &message);
+                    }
+                    None => println!("{address} needs unknown model {hash}"),
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+// address of the client behind notice
+fn sender(notice: &Message) -> Option<&String> {
+    match notice {
+        Message::Heartbeat { address }
+        | Message::JobAccepted { address, .. }
+        | Message::Report { address, .. }
+        | Message::Done { address, .. }
+        | Message::JobFailed { address, .. }
+        | Message::Need { address, .. } => Some(address),
+        Message::Register(node) => Some(&node.address),
+        _ => None,
+    }
 }
@@ -0,0 +1,507 @@
+use lefs::message::{Job, NodeInfo, HEARTBEAT_INTERVAL};
+use lefs::node::SyncMode;
+use lefs::{ConflictPolicy, Lefs, Model, QueueKind, TraceFormat};
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+
+// An experiment: the subnets a net is split into, the node simulating each
+// one if it matters and how the run goes. Written in TOML, or in JSON if the
+// file ends in .json, e.g.
+//
+//   address = "127.0.0.1:8888"
+//   last_cycle = 3000
+//   sync = "optimistic"
+//
+//   [[subnets]]
+//   model = "3subredes.subred0.json"
+//   # only the node registered as worker1 gets this one
+//   node = "worker1"
+//
+//   [[subnets]]
+//   model = "3subredes.subred1.json"
+//   # written by the node, under the --output-dir it was started with
+//   report = "subred1.report.json"
+//   timeline = "subred1.timeline.json"
+//   trace = { format = "jsonl", file = "subred1.jsonl" }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    // where the coordinator listens, the server_address clients are given
+    #[serde(default = "default_address")]
+    pub address: String,
+    // every subnet starts from its model's own cycle if missing
+    pub first_cycle: Option<usize>,
+    pub last_cycle: usize,
+    // unsynchronized, conservative or optimistic
+    #[serde(default = "default_sync")]
+    pub sync: String,
+    // first, priority or "random <seed>"
+    #[serde(default = "default_conflicts")]
+    pub conflicts: String,
+    // heap, calendar or ladder
+    #[serde(default = "default_queue")]
+    pub queue: String,
+    // milliseconds without a word from a running node, heartbeats included,
+    // before the run is called off
+    #[serde(default = "default_node_timeout_ms")]
+    pub node_timeout_ms: u64,
+    pub subnets: Vec<Subnet>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subnet {
+    // what the run calls the subnet, the model path if missing
+    pub name: Option<String>,
+    // relative to the manifest
+    pub model: String,
+    // id of the node that has to simulate the subnet, whatever its limits;
+    // any registered node that's free and takes the model otherwise
+    pub node: Option<String>,
+    // outputs are bare file names, see Job
+    pub snapshot: Option<String>,
+    pub trace: Option<Trace>,
+    pub timeline: Option<String>,
+    pub report: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Trace {
+    // csv or jsonl
+    pub format: String,
+    pub file: String,
+}
+
+// A manifest that checks out, waiting for nodes to run it
+#[derive(Debug)]
+pub struct Plan {
+    // node the subnet is pinned to, its model and its job but for the peers
+    pub subnets: Vec<(Option<String>, Model, Job)>,
+}
+
+fn default_address() -> String {
+    "127.0.0.1:8888".to_string()
+}
+
+fn default_sync() -> String {
+    "conservative".to_string()
+}
+
+fn default_conflicts() -> String {
+    "first".to_string()
+}
+
+fn default_queue() -> String {
+    "heap".to_string()
+}
+
+fn default_node_timeout_ms() -> u64 {
+    5000
+}
+
+impl Manifest {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|error| error.to_string())?;
+        if path.ends_with(".json") {
+            serde_json::from_str(&text).map_err(|error| error.to_string())
+        } else {
+            toml::from_str(&text).map_err(|error| error.to_string())
+        }
+    }
+
+    // the subnets to hand out, or everything wrong with the manifest; dir is
+    // where model paths start from
+    pub fn plan(&self, dir: &Path) -> Result<Plan, Vec<String>> {
+        let mut problems = vec![];
+
+        if let Err(error) = self.address.to_socket_addrs() {
+            problems.push(format!("address: {error}"));
+        }
+        if self
+            .first_cycle
+            .is_some_and(|first| first >= self.last_cycle)
+        {
+            problems.push("first_cycle: not before last_cycle".to_string());
+        }
+        let sync = self
+            .sync
+            .parse::<SyncMode>()
+            .map_err(|error| problems.push(format!("sync: {error}")));
+        let conflicts = self
+            .conflicts
+            .parse::<ConflictPolicy>()
+            .map_err(|error| problems.push(format!("conflicts: {error}")));
+        let queue = self
+            .queue
+            .parse::<QueueKind>()
+            .map_err(|error| problems.push(format!("queue: {error}")));
+        // a single late heartbeat shouldn't bring the run down
+        if u128::from(self.node_timeout_ms) < 2 * HEARTBEAT_INTERVAL.as_millis() {
+            let heartbeat = HEARTBEAT_INTERVAL.as_millis();
+            problems.push(format!(
+                "node_timeout_ms: under two heartbeats, {heartbeat} ms apart"
+            ));
+        }
+        if self.subnets.is_empty() {
+            problems.push("subnets: nothing to simulate".to_string());
+        }
+
+        // a node simulates one subnet at a time
+        let mut names = HashMap::new();
+        let mut nodes = HashMap::new();
+        let mut models = vec![];
+        let mut subnets = vec![];
+        let mut traces = vec![];
+        for (i, subnet) in self.subnets.iter().enumerate() {
+            // nodes won't write anywhere but their output directory
+            let outputs = [
+                ("snapshot", subnet.snapshot.as_ref()),
+                ("trace.file", subnet.trace.as_ref().map(|trace| &trace.file)),
+                ("timeline", subnet.timeline.as_ref()),
+                ("report", subnet.report.as_ref()),
+            ];
+            for (field, name) in outputs {
+                if let Some(name) =
+                    name.filter(|name| Path::new(name).file_name() != Some(name.as_ref()))
+                {
+                    problems.push(format!(
+                        "subnets[{i}].{field}: {name} isn't a bare file name"
+                    ));
+                }
+            }
+            let trace = subnet.trace.as_ref().map(|trace| {
+                let format = trace.format.parse::<TraceFormat>();
+                format.map(|format| (format, trace.file.clone()))
+            });
+            match trace.transpose() {
+                Ok(trace) => traces.push(trace),
+                Err(error) => problems.push(format!("subnets[{i}].trace.format: {error}")),
+            }
+
+            let name = subnet.name.as_ref().unwrap_or(&subnet.model);
+            if let Some(first) = names.insert(name, i) {
+                problems.push(format!(
+                    "subnets[{i}].name: {name} taken by subnets[{first}]"
+                ));
+            }
+            if let Some(node) = &subnet.node {
+                if let Some(first) = nodes.insert(node, i) {
+                    problems.push(format!(
+                        "subnets[{i}].node: {node} taken by subnets[{first}]"
+                    ));
+                }
+            }
+
+            let path = dir.join(&subnet.model);
+            let model =
+                Model::open(&path.to_string_lossy()).and_then(|model| Ok((model.lefs()?, model)));
+            match model {
+                Ok((lefs, model)) => {
+                    subnets.push(lefs);
+                    models.push(model);
+                }
+                Err(error) => problems.push(format!("subnets[{i}].model: {error}")),
+            }
+        }
+
+        // the subnets have to add up to a whole net
+        if !subnets.is_empty() && subnets.len() == self.subnets.len() {
+            match Lefs::merge(subnets) {
+                Ok(net) => {
+                    for transition in &net.transitions {
+                        for payload in &transition.external_payloads {
+                            problems.push(format!(
+                                "subnets: transition {} sends events to {}, which no subnet has",
+                                transition.id, payload.transition_id
+                            ));
+                        }
+                    }
+                }
+                Err(error) => problems.push(format!("subnets: {error}")),
+            }
+        }
+
+        let (Ok(sync), Ok(conflicts), Ok(queue)) = (sync, conflicts, queue) else {
+            return Err(problems);
+        };
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+
+        let subnets = self.subnets.iter().zip(models).zip(traces);
+        Ok(Plan {
+            subnets: subnets
+                .map(|((subnet, model), trace)| {
+                    let job = Job {
+                        path: subnet.name.clone().unwrap_or(subnet.model.clone()),
+                        model: model.hash.clone(),
+                        peers: vec![],
+                        sync,
+                        conflicts,
+                        queue,
+                        first_cycle: self.first_cycle,
+                        last_cycle: self.last_cycle,
+                        snapshot: subnet.snapshot.clone(),
+                        trace,
+                        timeline: subnet.timeline.clone(),
+                        report: subnet.report.clone(),
+                    };
+                    (subnet.node.clone(), model, job)
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Plan {
+    // the node and job for every subnet, None until enough nodes registered
+    pub fn assign(&self, nodes: &HashMap<String, NodeInfo>) -> Option<Vec<(NodeInfo, Job)>> {
+        let pinned: HashSet<&String> = self
+            .subnets
+            .iter()
+            .filter_map(|(node, _, _)| node.as_ref())
+            .collect();
+        let mut free: Vec<&NodeInfo> = nodes
+            .values()
+            .filter(|node| !pinned.contains(&node.id))
+            .collect();
+        free.sort_by_key(|node| (Reverse(node.cores), &node.id));
+
+        // largest models first, each to the node with the most cores that
+        // takes it
+        let mut order: Vec<usize> = (0..self.subnets.len()).collect();
+        order.sort_by_key(|&i| Reverse(self.subnets[i].1.bytes.len()));
+        let mut assigned = vec![None; self.subnets.len()];
+        for i in order {
+            let (node, model, _) = &self.subnets[i];
+            assigned[i] = match node {
+                Some(id) => Some(nodes.get(id)?),
+                None => {
+                    let size = model.bytes.len();
+                    let fits = |node: &&NodeInfo| node.max_model_size.is_none_or(|max| size <= max);
+                    Some(free.remove(free.iter().position(fits)?))
+                }
+            };
+        }
+        let assigned: Vec<&NodeInfo> = assigned.into_iter().flatten().collect();
+
+        // every node gets to know the others so subnets can exchange events
+        let jobs = self
+            .subnets
+            .iter()
+            .zip(&assigned)
+            .map(|((_, _, job), node)| {
+                let job = Job {
+                    peers: assigned
+                        .iter()
+                        .filter(|peer| peer.id != node.id)
+                        .map(|peer| peer.address.clone())
+                        .collect(),
+                    ..job.clone()
+                };
+                ((*node).clone(), job)
+            })
+            .collect();
+
+        Some(jobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // the 3subredes models sit with the client
+    fn dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("../client")
+    }
+
+    fn manifest(text: &str) -> Manifest {
+        toml::from_str(text).unwrap()
+    }
+
+    fn problems(text: &str) -> Vec<String> {
+        manifest(text).plan(&dir()).unwrap_err()
+    }
+
+    const SUBNETS: &str = r#"
+        [[subnets]]
+        model = "3subredes.subred0.json"
+
+        [[subnets]]
+        model = "3subredes.subred1.json"
+
+        [[subnets]]
+        model = "3subredes.subred2.json"
+    "#;
+
+    #[test]
+    fn plans_a_whole_net() {
+        let plan = manifest(&format!("last_cycle = 3000\n{SUBNETS}"))
+            .plan(&dir())
+            .unwrap();
+        assert_eq!(plan.subnets.len(), 3);
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let text = format!("last_cycle = 3000\nlast_cylce = 2000\n{SUBNETS}");
+        let error = toml::from_str::<Manifest>(&text).unwrap_err();
+        assert!(error.to_string().contains("unknown field `last_cylce`"));
+
+        let text = "last_cycle = 3000\n[[subnets]]\nmodel = \"a.json\"\nnodes = \"worker1\"";
+        let error = toml::from_str::<Manifest>(text).unwrap_err();
+        assert!(error.to_string().contains("unknown field `nodes`"));
+    }
+
+    #[test]
+    fn duplicate_subnets() {
+        let text = format!(
+            "last_cycle = 3000\n{SUBNETS}\n[[subnets]]\nmodel = \"3subredes.subred0.json\""
+        );
+        assert!(problems(&text)
+            .contains(&"subnets[3].name: 3subredes.subred0.json taken by subnets[0]".to_string()));
+    }
+
+    #[test]
+    fn missing_subnets() {
+        assert_eq!(
+            problems("last_cycle = 3000\nsubnets = []"),
+            ["subnets: nothing to simulate"]
+        );
+
+        // subred0 sends events to subred1 and subred2
+        let found = problems("last_cycle = 3000\n[[subnets]]\nmodel = \"3subredes.subred0.json\"");
+        assert!(!found.is_empty());
+        assert!(found
+            .iter()
+            .all(|problem| problem.starts_with("subnets: transition ")
+                && problem.ends_with(", which no subnet has")));
+
+        let found = problems(&format!(
+            "last_cycle = 3000\n{SUBNETS}\n[[subnets]]\nmodel = \"3subredes.subred3.json\""
+        ));
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("subnets[3].model: "));
+    }
+
+    #[test]
+    fn bad_sync_mode() {
+        let text = format!("last_cycle = 3000\nsync = \"pessimistic\"\n{SUBNETS}");
+        assert_eq!(problems(&text), ["sync: unknown sync mode pessimistic"]);
+    }
+
+    fn node(id: &str, cores: usize, max_model_size: Option<usize>) -> (String, NodeInfo) {
+        let node = NodeInfo {
+            id: id.to_string(),
+            address: format!("{id}:8000"),
+            cores,
+            max_model_size,
+        };
+        (id.to_string(), node)
+    }
+
+    // model of every subnet and the node it went to
+    fn assign(manifest: &str, nodes: &[(String, NodeInfo)]) -> Option<Vec<(String, String)>> {
+        let plan = self::manifest(manifest).plan(&dir()).unwrap();
+        let nodes = nodes.iter().cloned().collect();
+        let jobs = plan.assign(&nodes)?;
+        Some(
+            jobs.into_iter()
+                .map(|(node, job)| (job.path, node.id))
+                .collect(),
+        )
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        let pairs = pairs.iter();
+        pairs
+            .map(|&(a, b)| (a.to_string(), b.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn largest_model_to_most_cores() {
+        // subred0 is the largest model
+        let manifest = format!("last_cycle = 3000\n{SUBNETS}");
+        let nodes = [node("a", 2, None), node("b", 8, None), node("c", 4, None)];
+        let jobs = assign(&manifest, &nodes).unwrap();
+        assert_eq!(
+            jobs[0],
+            ("3subredes.subred0.json".to_string(), "b".to_string())
+        );
+
+        // unless the node doesn't take it
+        let nodes = [
+            node("a", 2, None),
+            node("b", 8, Some(1000)),
+            node("c", 4, None),
+        ];
+        let jobs = assign(&manifest, &nodes).unwrap();
+        assert_eq!(
+            jobs[0],
+            ("3subredes.subred0.json".to_string(), "c".to_string())
+        );
+    }
+
+    #[test]
+    fn pinned_and_peers() {
+        let manifest = "last_cycle = 3000\n\
+            [[subnets]]\nmodel = \"3subredes.subred0.json\"\n\
+            [[subnets]]\nmodel = \"3subredes.subred1.json\"\nnode = \"a\"\n\
+            [[subnets]]\nmodel = \"3subredes.subred2.json\"";
+        let nodes = [
+            node("a", 1, Some(1)),
+            node("b", 8, None),
+            node("c", 4, None),
+        ];
+        assert_eq!(
+            assign(manifest, &nodes).unwrap(),
+            pairs(&[
+                ("3subredes.subred0.json", "b"),
+                ("3subredes.subred1.json", "a"),
+                ("3subredes.subred2.json", "c"),
+            ])
+        );
+
+        let plan = self::manifest(manifest).plan(&dir()).unwrap();
+        let jobs = plan.assign(&nodes.iter().cloned().collect()).unwrap();
+        for (node, job) in jobs {
+            assert_eq!(job.peers.len(), 2);
+            assert!(!job.peers.contains(&node.address));
+        }
+    }
+
+    #[test]
+    fn not_enough_nodes() {
+        let manifest = format!("last_cycle = 3000\n{SUBNETS}");
+        assert_eq!(
+            assign(&manifest, &[node("a", 2, None), node("b", 8, None)]),
+            None
+        );
+        // subred0 only fits on c
+        let nodes = [
+            node("a", 2, Some(1000)),
+            node("b", 8, Some(1000)),
+            node("c", 4, None),
+        ];
+        assert!(assign(&manifest, &nodes).is_some());
+        let nodes = [
+            node("a", 2, Some(1000)),
+            node("b", 8, Some(1000)),
+            node("c", 4, Some(1000)),
+        ];
+        assert_eq!(assign(&manifest, &nodes), None);
+
+        // a pinned node that never registered
+        let manifest = format!("last_cycle = 3000\n{SUBNETS}\nnode = \"d\"");
+        let nodes = [node("a", 2, None), node("b", 8, None), node("c", 4, None)];
+        assert_eq!(assign(&manifest, &nodes), None);
+    }
+}